@@ -0,0 +1,30 @@
+//! Benchmarks of subkey derivation with and without the derived key cache
+
+use std::path::Path;
+
+use criterion::{black_box, Criterion};
+use rachat_common::crypto::{KDFSecretKey, Purpose};
+
+/// Compares deriving the key of a mutable file from the cache and from scratch
+fn derive_subkey(c: &mut Criterion) {
+    let root_key = KDFSecretKey::new();
+    let path = Path::new("media/0123456789abcdef");
+    let mut group = c.benchmark_group("derive_subkey");
+
+    group.bench_function("cached", |b| {
+        b.iter(|| root_key.derive_subkey(black_box(Purpose::File(path))));
+    });
+    group.bench_function("uncached", |b| {
+        b.iter(|| {
+            root_key.clear_key_cache();
+            root_key.derive_subkey(black_box(Purpose::File(path)))
+        });
+    });
+    group.finish();
+}
+
+fn main() {
+    let mut criterion = Criterion::default().configure_from_args();
+    derive_subkey(&mut criterion);
+    criterion.final_summary();
+}
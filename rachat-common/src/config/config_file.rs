@@ -4,8 +4,44 @@ use std::{borrow::Cow, path::Path};
 
 use eyre::{Context, Result};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{OnceCell, RwLock};
-use tracing::error;
+use tokio::{
+    fs,
+    sync::{OnceCell, RwLock},
+};
+
+use crate::crypto::mutable_file;
+
+/// Size and position of the main window
+///
+/// Values missing from the configuration file fall back to the defaults.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowGeometry {
+    /// Width of the window, while it is not maximized
+    pub width: i32,
+    /// Height of the window, while it is not maximized
+    pub height: i32,
+    /// Horizontal position of the window, `None` lets the window manager place it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<i32>,
+    /// Vertical position of the window, `None` lets the window manager place it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<i32>,
+    /// Whether the window is maximized
+    pub maximized: bool,
+}
+
+impl Default for WindowGeometry {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            x: None,
+            y: None,
+            maximized: false,
+        }
+    }
+}
 
 /// Data stored in the configuration file
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -13,21 +49,40 @@ struct ConfigFileData<'cfg> {
     /// Profile to use
     #[serde(skip_serializing_if = "Option::is_none")]
     default_profile: Option<Cow<'cfg, str>>,
+    /// Geometry of the main window when it was last closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    window: Option<WindowGeometry>,
+    /// Settings this version doesn't know about, kept so that writing the file doesn't drop them
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl<'cfg> ConfigFileData<'cfg> {
-    async fn load(file_name: impl AsRef<Path>) -> Result<ConfigFileData<'cfg>> {
-        match std::fs::read_to_string(file_name) {
-            Ok(s) => Ok(serde_json::from_str(&s).context("Parsing configuration file")?),
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    return Ok(Self::default());
-                }
-                error!("Failed to read configuration file: {e:#?}");
-                Ok(Self::default())
-            }
+    /// Loads the configuration data from disk, falling back to defaults if the file is missing
+    ///
+    /// Any other error is returned, so that the defaults are never written over a file that just couldn't be read.
+    async fn load(file_name: impl AsRef<Path> + Send) -> Result<ConfigFileData<'cfg>> {
+        let file_name = file_name.as_ref();
+        match fs::read_to_string(file_name).await {
+            Ok(s) => Self::parse(file_name, &s),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Reading configuration file {}", file_name.display())),
         }
     }
+
+    /// Parses the contents of the configuration file
+    ///
+    /// The file name is only used to give the error context.
+    fn parse(file_name: &Path, contents: &str) -> Result<Self> {
+        serde_json::from_str(contents).map_err(|e| {
+            let (line, column) = (e.line(), e.column());
+            eyre::Error::new(e).wrap_err(format!(
+                "Parsing configuration file {} (line {line}, column {column})",
+                file_name.display()
+            ))
+        })
+    }
 }
 
 /// Config file structure
@@ -46,6 +101,7 @@ impl<'cfg> ConfigFile<'cfg> {
     }
 
     /// Creates a new configuration file, in a const context
+    #[must_use]
     pub const fn const_new(file_name: Cow<'cfg, Path>) -> Self {
         Self {
             data: OnceCell::const_new(),
@@ -53,6 +109,7 @@ impl<'cfg> ConfigFile<'cfg> {
         }
     }
 
+    /// Returns the configuration data, loading it on first access
     async fn data(&self) -> Result<&RwLock<ConfigFileData<'cfg>>> {
         self.data
             .get_or_try_init(|| async move {
@@ -63,7 +120,116 @@ impl<'cfg> ConfigFile<'cfg> {
     }
 
     /// Returns the default profile name
+    ///
+    /// # Errors
+    /// This function returns an error if the configuration file exists but cannot be read or parsed.
     pub async fn default_profile(&self) -> Result<Option<Cow<'_, str>>> {
         Ok(self.data().await?.read().await.default_profile.clone())
     }
+
+    /// Returns the stored geometry of the main window
+    ///
+    /// # Errors
+    /// This function returns an error if the configuration file exists but cannot be read or parsed.
+    pub async fn window_geometry(&self) -> Result<Option<WindowGeometry>> {
+        Ok(self.data().await?.read().await.window)
+    }
+
+    /// Stores the geometry of the main window, writing the configuration file
+    ///
+    /// The file is replaced atomically, so a crash never leaves it truncated.
+    ///
+    /// # Errors
+    /// This function returns an error if the configuration file cannot be read, parsed or written.
+    pub async fn set_window_geometry(&self, geometry: WindowGeometry) -> Result<()> {
+        let mut data = self.data().await?.write().await;
+        data.window = Some(geometry);
+        let contents =
+            serde_json::to_string_pretty(&*data).context("Serializing the configuration")?;
+        // The lock is held while writing, so that concurrent writes land in order
+        mutable_file::write_atomically(&self.file_name, contents.as_bytes())
+            .await
+            .with_context(|| format!("Writing {}", self.file_name.display()))?;
+        drop(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{ConfigFile, ConfigFileData, WindowGeometry};
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn test_invalid_config_reports_path() {
+        let path = Path::new("/nonexistent/config.json");
+        let result = ConfigFileData::parse(path, "{\n  \"default_profile\": 12\n}");
+        assert!(result.is_err());
+        if let Err(err) = result {
+            let message = format!("{err:#}");
+            assert!(message.contains("/nonexistent/config.json"), "{message}");
+            assert!(message.contains("line 2"), "{message}");
+        }
+    }
+
+    #[test]
+    fn test_partial_window_geometry_uses_defaults() -> eyre::Result<()> {
+        let data = ConfigFileData::parse(
+            Path::new("config.json"),
+            r#"{"window": {"width": 800, "maximized": true}}"#,
+        )?;
+        assert_eq!(
+            data.window,
+            Some(WindowGeometry {
+                width: 800,
+                maximized: true,
+                ..WindowGeometry::default()
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_window_geometry_is_written() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"default_profile": "work", "theme": {"dark": true}}"#,
+        )?;
+
+        let geometry = WindowGeometry {
+            x: Some(10),
+            y: Some(20),
+            ..WindowGeometry::default()
+        };
+        ConfigFile::new(path.as_path())
+            .set_window_geometry(geometry)
+            .await?;
+        let reloaded = ConfigFile::new(path.as_path());
+        assert_eq!(reloaded.window_geometry().await?, Some(geometry));
+        assert_eq!(reloaded.default_profile().await?.as_deref(), Some("work"));
+        let contents: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(contents["theme"], serde_json::json!({"dark": true}));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_config_is_not_overwritten() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        // A directory can't be read as a file, but unlike a missing file this isn't NotFound
+        let path = dir.join("config.json");
+        std::fs::create_dir_all(&path)?;
+
+        let file = ConfigFile::new(path.as_path());
+        assert!(file.default_profile().await.is_err());
+        assert!(file
+            .set_window_geometry(WindowGeometry::default())
+            .await
+            .is_err());
+        assert!(path.is_dir());
+        Ok(())
+    }
 }
@@ -2,31 +2,59 @@
 
 pub mod config_file;
 
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex, PoisonError, Weak},
+    time::Duration,
+};
 
-use config_file::ConfigFile;
+use config_file::{ConfigFile, WindowGeometry};
 use directories_next::ProjectDirs;
 use eyre::Result;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::error;
+
+/// How long the window geometry has to stay the same before it is written
+///
+/// Resizing or moving the window reports a new geometry for every frame, which would otherwise rewrite the configuration file just as often.
+const WINDOW_SAVE_DELAY: Duration = Duration::from_millis(500);
 
 /// Configuration storage
 #[derive(Debug)]
 pub struct Config {
-    global_config: ConfigFile<'static>,
+    /// The global configuration file
+    global_file: ConfigFile<'static>,
+    /// Window geometry that has not been written yet
+    pending_window_geometry: watch::Sender<Option<WindowGeometry>>,
+    /// Task writing the window geometry whenever it settles
+    window_save_task: Mutex<Option<JoinHandle<()>>>,
+    /// Held while the window geometry is written, so that an older geometry can't overwrite a newer one
+    window_write_lock: tokio::sync::Mutex<()>,
 }
 
 impl Config {
     /// Creates a new configuration storage
     pub fn new(dirs: &ProjectDirs) -> Arc<Self> {
-        Arc::new(Self {
-            global_config: ConfigFile::const_new(dirs.config_dir().join("config.json").into()),
-        })
+        Arc::new(Self::with_config_file(ConfigFile::const_new(
+            dirs.config_dir().join("config.json").into(),
+        )))
+    }
+
+    /// Creates a new configuration storage backed by a configuration file
+    fn with_config_file(global_file: ConfigFile<'static>) -> Self {
+        Self {
+            global_file,
+            pending_window_geometry: watch::Sender::new(None),
+            window_save_task: Mutex::new(None),
+            window_write_lock: tokio::sync::Mutex::new(()),
+        }
     }
 
     /// Returns the default profile name
     ///
     /// This setting can only be changed globally
     pub async fn default_profile(&self) -> Result<Cow<'_, str>> {
-        self.global_config
+        self.global_file
             .default_profile()
             .await
             .map(|o| o.unwrap_or_else(|| "default".into()))
@@ -42,4 +70,153 @@ impl Config {
             self.default_profile().await
         }
     }
+
+    /// Returns the geometry of the main window, as it was when the window was last closed
+    ///
+    /// # Errors
+    /// This function returns an error if the configuration file exists but cannot be parsed.
+    pub async fn window_geometry(&self) -> Result<WindowGeometry> {
+        let pending = *self.pending_window_geometry.borrow();
+        if let Some(geometry) = pending {
+            return Ok(geometry);
+        }
+        Ok(self
+            .global_file
+            .window_geometry()
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Records a new geometry of the main window
+    ///
+    /// The geometry is written once it hasn't changed for a moment, or when [`flush_window_geometry`](Self::flush_window_geometry) is called. This doesn't wait, so it can be called from the UI thread for every frame of a resize, but it has to be called within the tokio runtime.
+    pub fn set_window_geometry(self: &Arc<Self>, geometry: WindowGeometry) {
+        self.pending_window_geometry.send_replace(Some(geometry));
+        let mut task = self
+            .window_save_task
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if task.as_ref().is_none_or(JoinHandle::is_finished) {
+            let mut changes = self.pending_window_geometry.subscribe();
+            changes.mark_changed();
+            *task = Some(tokio::spawn(save_window_geometry_when_settled(
+                Arc::downgrade(self),
+                changes,
+            )));
+        }
+    }
+
+    /// Writes the window geometry, if it has changed since it was last written
+    ///
+    /// # Errors
+    /// This function returns an error if the configuration file cannot be parsed or written.
+    pub async fn flush_window_geometry(&self) -> Result<()> {
+        let guard = self.window_write_lock.lock().await;
+        let mut geometry = None;
+        // Taking the geometry is not a change the save task has to wait for
+        self.pending_window_geometry.send_if_modified(|pending| {
+            geometry = pending.take();
+            false
+        });
+        let result = match geometry {
+            Some(geometry) => self.global_file.set_window_geometry(geometry).await,
+            None => Ok(()),
+        };
+        drop(guard);
+        result
+    }
+}
+
+/// Writes the window geometry whenever it has not changed for [`WINDOW_SAVE_DELAY`]
+///
+/// The task ends once the configuration storage has been dropped.
+async fn save_window_geometry_when_settled(
+    config: Weak<Config>,
+    mut changes: watch::Receiver<Option<WindowGeometry>>,
+) {
+    while changes.changed().await.is_ok() {
+        loop {
+            match tokio::time::timeout(WINDOW_SAVE_DELAY, changes.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => return,
+                Err(_) => break,
+            }
+        }
+        let Some(config) = config.upgrade() else {
+            return;
+        };
+        if let Err(e) = config.flush_window_geometry().await {
+            error!("Failed to save the window geometry: {e:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use super::{config_file::ConfigFile, Config, WindowGeometry, WINDOW_SAVE_DELAY};
+    use crate::test_utils::TempDir;
+
+    #[tokio::test]
+    async fn test_window_geometry_is_coalesced() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.join("nested").join("config.json");
+        let config = Arc::new(Config::with_config_file(ConfigFile::new(path.clone())));
+
+        for width in [700, 800, 900] {
+            config.set_window_geometry(WindowGeometry {
+                width,
+                ..WindowGeometry::default()
+            });
+        }
+        assert!(!path.exists());
+        assert_eq!(config.window_geometry().await?.width, 900);
+
+        config.flush_window_geometry().await?;
+        let stored = ConfigFile::new(path.clone()).window_geometry().await?;
+        assert_eq!(stored.map(|geometry| geometry.width), Some(900));
+        Ok(())
+    }
+
+    /// Waits until the stored window width is `width`, failing after a few seconds
+    async fn wait_for_stored_width(path: &Path, width: i32) -> eyre::Result<()> {
+        for _ in 0..100 {
+            let stored = ConfigFile::new(path).window_geometry().await?;
+            if stored.map(|geometry| geometry.width) == Some(width) {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        eyre::bail!("the window width {width} was never written");
+    }
+
+    #[tokio::test]
+    async fn test_window_geometry_is_saved_whenever_it_settles() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.join("config.json");
+        let config = Arc::new(Config::with_config_file(ConfigFile::new(path.clone())));
+
+        for width in [700, 800] {
+            config.set_window_geometry(WindowGeometry {
+                width,
+                ..WindowGeometry::default()
+            });
+            wait_for_stored_width(&path, width).await?;
+        }
+
+        // A write in progress never overwrites a newer geometry
+        config.set_window_geometry(WindowGeometry {
+            width: 900,
+            ..WindowGeometry::default()
+        });
+        tokio::time::sleep(WINDOW_SAVE_DELAY).await;
+        config.set_window_geometry(WindowGeometry {
+            width: 1000,
+            ..WindowGeometry::default()
+        });
+        config.flush_window_geometry().await?;
+        tokio::time::sleep(WINDOW_SAVE_DELAY * 2).await;
+        wait_for_stored_width(&path, 1000).await
+    }
 }
@@ -0,0 +1,96 @@
+//! Passphrase-encrypted bundles
+//!
+//! Bundles carry secrets between machines, where the root key is not available. The plaintext is encrypted with XChaCha20-Poly1305, under a key derived from the passphrase with Argon2id, the same way as [secret files](super::file_backend). Bundles are stored as CBOR maps of:
+//!
+//! - `version`: format version of the bundle, currently 1
+//! - `salt`: 16 byte salt for deriving the key from the passphrase
+//! - `nonce`: 24 byte nonce the plaintext was encrypted with
+//! - `ciphertext`: the encrypted plaintext, followed by the 16 byte authentication tag
+//!
+//! The caller supplies associated data naming the kind of bundle, so that one kind of bundle can't be passed off as another.
+
+use eyre::{Context, Result};
+use secrecy::Secret;
+use serde::{Deserialize, Serialize};
+
+use super::file_backend::Sealed;
+
+/// Current format version of bundles
+const BUNDLE_VERSION: u8 = 1;
+
+/// Contents of a bundle
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    /// Format version of the bundle
+    version: u8,
+    /// Salt for deriving the key from the passphrase
+    salt: [u8; 16],
+    /// Nonce the plaintext was encrypted with
+    nonce: [u8; 24],
+    /// The encrypted plaintext
+    ciphertext: Vec<u8>,
+}
+
+/// Encrypts `plaintext` into a bundle protected by `passphrase`
+///
+/// Deriving the key is deliberately slow, so async callers should run this on a blocking thread.
+///
+/// # Errors
+/// This function will return an error if deriving the key or encrypting fails.
+pub fn seal(passphrase: &Secret<String>, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let Sealed {
+        salt,
+        nonce,
+        ciphertext,
+    } = Sealed::seal(passphrase, plaintext, aad).context("Encrypting the bundle")?;
+
+    let mut bundle = Vec::new();
+    ciborium::ser::into_writer(
+        &Bundle {
+            version: BUNDLE_VERSION,
+            salt,
+            nonce,
+            ciphertext,
+        },
+        &mut bundle,
+    )
+    .context("Serializing the bundle")?;
+    Ok(bundle)
+}
+
+/// Decrypts a bundle protected by `passphrase`, returning the plaintext
+///
+/// The returned plaintext has to be zeroized by the caller. Deriving the key is deliberately slow, so async callers should run this on a blocking thread.
+///
+/// # Errors
+/// This function will return an error if the bundle is malformed or of an unknown version, or if the passphrase or the associated data is wrong.
+pub fn open(passphrase: &Secret<String>, bundle: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let bundle: Bundle = ciborium::de::from_reader(bundle).context("Parsing the bundle")?;
+    if bundle.version != BUNDLE_VERSION {
+        eyre::bail!("Unsupported bundle version {}", bundle.version);
+    }
+    Sealed {
+        salt: bundle.salt,
+        nonce: bundle.nonce,
+        ciphertext: bundle.ciphertext,
+    }
+    .open(passphrase, aad)
+    .context("Decrypting the bundle")
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use super::{open, seal};
+
+    #[test]
+    fn test_bundle_round_trip() -> eyre::Result<()> {
+        let passphrase = Secret::new("correct horse battery staple".to_owned());
+        let bundle = seal(&passphrase, b"secret", b"test")?;
+        assert_eq!(open(&passphrase, &bundle, b"test")?, b"secret");
+        assert!(open(&Secret::new("wrong".to_owned()), &bundle, b"test").is_err());
+        assert!(open(&passphrase, &bundle, b"other").is_err());
+        Ok(())
+    }
+}
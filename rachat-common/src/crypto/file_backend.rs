@@ -0,0 +1,363 @@
+//! Passphrase-protected secret files
+//!
+//! On systems without a keyring, secrets are stored in a file instead. The file is a CBOR map of a salt, a nonce and a ciphertext. The plaintext is a CBOR map of entry names to secrets, encrypted with XChaCha20-Poly1305 under a key derived from a user passphrase with Argon2id. Every write re-encrypts the whole file with a fresh salt and nonce, and replaces it atomically.
+//!
+//! The backend of a profile is chosen by its [`SecretBackend`]. [`KDFSecretKey::load`](super::KDFSecretKey::load) loads the root key from it, which takes the configuration directory and the passphrase on top of the backend, as the file backend needs both.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use argon2::Argon2;
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use eyre::{Context, Result};
+use rand::{thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret, Zeroize};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Mutex};
+
+use super::{mutable_file, Keyring, SecretStore};
+
+/// Name of the secret file in the configuration directory of a profile
+const SECRET_FILE: &str = "secrets.cbor";
+
+/// Associated data of secret files
+const SECRET_FILE_AAD: &[u8] = b"rs.chir.rachat.secret-file";
+
+/// Where the root key of a profile is stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretBackend {
+    /// In the keyring of the operating system
+    #[default]
+    Keyring,
+    /// In a file in the configuration directory of the profile, protected by a passphrase
+    File,
+}
+
+impl SecretBackend {
+    /// Returns whether this is the default backend
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Called by serde, which passes a reference
+    pub(crate) fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Opens the secret store of a profile
+    ///
+    /// `passphrase` is only used by the file backend.
+    ///
+    /// # Errors
+    /// This function returns a [`PassphraseRequiredError`] if the file backend is selected, but no passphrase was given.
+    pub fn open(
+        self,
+        config_dir: &Path,
+        passphrase: Option<&Secret<String>>,
+    ) -> Result<Arc<dyn SecretStore>> {
+        match self {
+            Self::Keyring => Ok(Arc::new(Keyring)),
+            Self::File => {
+                let passphrase = passphrase.ok_or(PassphraseRequiredError)?;
+                Ok(Arc::new(FileSecretStore::new(
+                    config_dir.join(SECRET_FILE),
+                    Secret::new(passphrase.expose_secret().clone()),
+                )))
+            }
+        }
+    }
+}
+
+/// Error returned when a profile keeps its secrets in a file, but no passphrase for it was given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassphraseRequiredError;
+
+impl fmt::Display for PassphraseRequiredError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The secrets of this profile are protected by a passphrase"
+        )
+    }
+}
+
+impl std::error::Error for PassphraseRequiredError {}
+
+/// Secret store keeping secrets in a passphrase-protected file
+#[derive(Debug)]
+pub struct FileSecretStore {
+    /// Path of the secret file
+    path: PathBuf,
+    /// Passphrase the secret file is protected with
+    passphrase: Arc<Secret<String>>,
+    /// Held while the file is being updated, so that concurrent updates don't get lost
+    lock: Mutex<()>,
+}
+
+/// Data encrypted under a key derived from a passphrase
+///
+/// This is the contents of a secret file, and the encrypted part of a [bundle](super::bundle).
+#[derive(Serialize, Deserialize)]
+pub(super) struct Sealed {
+    /// Salt for deriving the key from the passphrase
+    pub(super) salt: [u8; 16],
+    /// Nonce the plaintext was encrypted with
+    pub(super) nonce: [u8; 24],
+    /// The encrypted plaintext, followed by the authentication tag
+    pub(super) ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    /// Encrypts `plaintext` under a key derived from `passphrase`, with a fresh salt and nonce
+    ///
+    /// # Errors
+    /// This function will return an error if deriving the key or encrypting fails.
+    pub(super) fn seal(passphrase: &Secret<String>, plaintext: &[u8], aad: &[u8]) -> Result<Self> {
+        let salt: [u8; 16] = thread_rng().r#gen();
+        let nonce = XChaCha20Poly1305::generate_nonce(thread_rng());
+        let mut key = wrapping_key(passphrase, &salt)?;
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        key.zeroize();
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| eyre::eyre!("Encryption failed"))?;
+        Ok(Self {
+            salt,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypts the plaintext with a key derived from `passphrase`
+    ///
+    /// The returned plaintext has to be zeroized by the caller.
+    ///
+    /// # Errors
+    /// This function will return an error if deriving the key fails, or if the passphrase or the associated data is wrong.
+    pub(super) fn open(&self, passphrase: &Secret<String>, aad: &[u8]) -> Result<Vec<u8>> {
+        let mut key = wrapping_key(passphrase, &self.salt)?;
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        key.zeroize();
+        cipher
+            .decrypt(
+                XNonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| eyre::eyre!("Wrong passphrase, or the data has been tampered with"))
+    }
+}
+
+/// Decrypted secrets of a secret file, zeroized when dropped
+#[derive(Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Secrets(BTreeMap<String, String>);
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        for secret in self.0.values_mut() {
+            secret.zeroize();
+        }
+    }
+}
+
+impl FileSecretStore {
+    /// Creates a secret store backed by the file at `path`
+    ///
+    /// The file is created on the first write.
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>, passphrase: Secret<String>) -> Self {
+        Self {
+            path: path.into(),
+            passphrase: Arc::new(passphrase),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Reads and decrypts the secret file, returning no secrets if it doesn't exist
+    async fn load(&self) -> Result<Secrets> {
+        let contents = match fs::read(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Secrets::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Reading secret file {}", self.path.display()))
+            }
+        };
+        let path = self.path.clone();
+        let passphrase = Arc::clone(&self.passphrase);
+        // Deriving the key is deliberately slow, so it must not hold up an async worker thread
+        tokio::task::spawn_blocking(move || decrypt_secrets(&path, &passphrase, &contents))
+            .await
+            .context("Decrypting the secret file")?
+    }
+
+    /// Encrypts the secrets and replaces the secret file with them
+    async fn save(&self, secrets: &Secrets) -> Result<()> {
+        let mut plaintext = Vec::new();
+        let result =
+            ciborium::ser::into_writer(secrets, &mut plaintext).context("Serializing secrets");
+        if let Err(e) = result {
+            plaintext.zeroize();
+            return Err(e);
+        }
+        let passphrase = Arc::clone(&self.passphrase);
+        let contents = tokio::task::spawn_blocking(move || {
+            let contents = encrypt_secrets(&passphrase, &plaintext);
+            plaintext.zeroize();
+            contents
+        })
+        .await
+        .context("Encrypting the secret file")??;
+
+        // The file holds the only copy of the root key, so it is never left half-written
+        mutable_file::write_atomically(&self.path, &contents)
+            .await
+            .with_context(|| format!("Writing secret file {}", self.path.display()))
+    }
+}
+
+#[async_trait]
+impl SecretStore for FileSecretStore {
+    async fn get(&self, name: &str) -> Result<Option<Secret<String>>> {
+        Ok(self
+            .load()
+            .await?
+            .0
+            .get(name)
+            .map(|secret| Secret::new(secret.clone())))
+    }
+
+    async fn set(&self, name: &str, secret: Secret<String>) -> Result<()> {
+        let guard = self.lock.lock().await;
+        let mut secrets = self.load().await?;
+        if let Some(mut old) = secrets
+            .0
+            .insert(name.to_owned(), secret.expose_secret().clone())
+        {
+            old.zeroize();
+        }
+        self.save(&secrets).await?;
+        drop(guard);
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let guard = self.lock.lock().await;
+        let mut secrets = self.load().await?;
+        if let Some(mut old) = secrets.0.remove(name) {
+            old.zeroize();
+            self.save(&secrets).await?;
+        }
+        drop(guard);
+        Ok(())
+    }
+}
+
+/// Decrypts the contents of a secret file
+fn decrypt_secrets(path: &Path, passphrase: &Secret<String>, contents: &[u8]) -> Result<Secrets> {
+    let secret_file: Sealed = ciborium::de::from_reader(contents)
+        .with_context(|| format!("Parsing secret file {}", path.display()))?;
+    let mut plaintext = secret_file
+        .open(passphrase, SECRET_FILE_AAD)
+        .with_context(|| format!("Decrypting secret file {}", path.display()))?;
+    let secrets = ciborium::de::from_reader(plaintext.as_slice())
+        .with_context(|| format!("Parsing the secrets in {}", path.display()));
+    plaintext.zeroize();
+    secrets
+}
+
+/// Encrypts serialized secrets into the contents of a secret file
+fn encrypt_secrets(passphrase: &Secret<String>, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let secret_file = Sealed::seal(passphrase, plaintext, SECRET_FILE_AAD)
+        .context("Encrypting the secret file")?;
+    let mut contents = Vec::new();
+    ciborium::ser::into_writer(&secret_file, &mut contents).context("Serializing secret file")?;
+    Ok(contents)
+}
+
+/// Derives a key wrapping secrets from a passphrase
+///
+/// The returned key has to be zeroized by the caller.
+fn wrapping_key(passphrase: &Secret<String>, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.expose_secret().as_bytes(), salt, &mut key)
+        .map_err(|e| eyre::eyre!("Deriving a key from the passphrase: {e}"))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::{ExposeSecret, Secret};
+
+    use super::{FileSecretStore, PassphraseRequiredError, SecretBackend};
+    use crate::{
+        crypto::{KDFSecretKey, Purpose, SecretStore},
+        test_utils::TempDir,
+    };
+
+    #[tokio::test]
+    async fn test_secret_file_round_trip() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.join("secrets.cbor");
+        let store = FileSecretStore::new(
+            &path,
+            Secret::new("correct horse battery staple".to_owned()),
+        );
+
+        let created = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        let loaded = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        assert_eq!(
+            created.subkey_passphrase(Purpose::Test).expose_secret(),
+            loaded.subkey_passphrase(Purpose::Test).expose_secret()
+        );
+        let other = KDFSecretKey::load_from_store(&store, "other", 0).await?;
+        assert_ne!(created.expose_bytes(), other.expose_bytes());
+
+        // No temporary files are left behind
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        let wrong_passphrase = FileSecretStore::new(&path, Secret::new("wrong".to_owned()));
+        assert!(KDFSecretKey::load_from_store(&wrong_passphrase, "test", 0)
+            .await
+            .is_err());
+
+        store.delete("other-key").await?;
+        assert!(store.get("other-key").await?.is_none());
+        assert!(store.get("test-key").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_uses_the_selected_backend() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let error = KDFSecretKey::load("test", SecretBackend::File, &dir, None)
+            .await
+            .err()
+            .ok_or_else(|| eyre::eyre!("loaded without a passphrase"))?;
+        assert!(error.downcast_ref::<PassphraseRequiredError>().is_some());
+
+        let passphrase = Secret::new("correct horse battery staple".to_owned());
+        let created =
+            KDFSecretKey::load("test", SecretBackend::File, &dir, Some(&passphrase)).await?;
+        let loaded =
+            KDFSecretKey::load("test", SecretBackend::File, &dir, Some(&passphrase)).await?;
+        assert_eq!(created.expose_bytes(), loaded.expose_bytes());
+        assert!(dir.join("secrets.cbor").is_file());
+        Ok(())
+    }
+}
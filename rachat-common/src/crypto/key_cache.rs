@@ -0,0 +1,88 @@
+//! Cache of derived keys
+//!
+//! Deriving a key is cheap, but opening many files (like the media cache) derives the same keys over and over. The cache is bounded, and evicted keys are zeroized when they are dropped.
+
+use std::collections::HashMap;
+
+use secrecy::{ExposeSecret, Secret};
+
+/// Maximum number of derived keys kept in the cache
+pub(super) const KEY_CACHE_CAPACITY: usize = 256;
+
+/// Bounded least recently used cache of derived keys, keyed by KDF context
+#[derive(Debug, Default)]
+pub(super) struct DerivedKeyCache {
+    /// Cached keys, with the tick they were last used at
+    keys: HashMap<String, (Secret<[u8; 32]>, u64)>,
+    /// Incremented on every access
+    tick: u64,
+}
+
+impl DerivedKeyCache {
+    /// Returns the key derived for `context`, deriving it with `derive` if it is not cached
+    ///
+    /// The returned copy has to be zeroized by the caller.
+    pub(super) fn get_or_derive(
+        &mut self,
+        context: &str,
+        derive: impl FnOnce() -> [u8; 32],
+    ) -> [u8; 32] {
+        self.tick += 1;
+        if let Some((key, last_used)) = self.keys.get_mut(context) {
+            *last_used = self.tick;
+            return *key.expose_secret();
+        }
+
+        if self.keys.len() >= KEY_CACHE_CAPACITY {
+            let oldest = self
+                .keys
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(context, _)| context.clone());
+            if let Some(oldest) = oldest {
+                self.keys.remove(&oldest);
+            }
+        }
+        let key = derive();
+        self.keys
+            .insert(context.to_owned(), (Secret::new(key), self.tick));
+        key
+    }
+
+    /// Removes all keys from the cache
+    pub(super) fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    /// Returns the number of cached keys
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DerivedKeyCache, KEY_CACHE_CAPACITY};
+
+    #[test]
+    fn test_cache_is_bounded_and_keeps_recent_keys() {
+        let mut cache = DerivedKeyCache::default();
+        let mut derivations = 0;
+        for i in 0..=KEY_CACHE_CAPACITY {
+            cache.get_or_derive(&format!("{i}"), || {
+                derivations += 1;
+                [0; 32]
+            });
+            // Keep the first key in use, so it is never the least recently used one
+            cache.get_or_derive("0", || [0; 32]);
+        }
+        assert_eq!(derivations, KEY_CACHE_CAPACITY + 1);
+        assert_eq!(cache.len(), KEY_CACHE_CAPACITY);
+
+        let key = cache.get_or_derive("0", || [1; 32]);
+        assert_eq!(key, [0; 32]);
+        let key = cache.get_or_derive("1", || [1; 32]);
+        assert_eq!(key, [1; 32]);
+    }
+}
@@ -2,20 +2,121 @@
 use std::{
     fmt::{Debug, Display},
     path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
 };
 
 use eyre::{Context, Result};
-use keyring::Entry;
 use rand::{distributions::Alphanumeric, CryptoRng, Rng, SeedableRng};
 use secrecy::{ExposeSecret, Secret, Zeroize};
+use subtle::ConstantTimeEq;
+use tracing::warn;
 
-use self::mutable_file::MutableFile;
+pub use self::{
+    file_backend::{FileSecretStore, PassphraseRequiredError, SecretBackend},
+    secret_store::{Keyring, SecretStore},
+};
+use self::{key_cache::DerivedKeyCache, mutable_file::MutableFile};
 
+pub(crate) mod bundle;
+mod file_backend;
+mod key_cache;
 pub mod mutable_file;
+mod rotation;
+pub mod secret_store;
+
+/// Keyring service all entries are stored under
+const KEYRING_SERVICE: &str = "rs.chir.rachat";
+
+/// Number of times loading the root key is retried if the keyring is temporarily unavailable
+pub const DEFAULT_KEYRING_RETRIES: u32 = 5;
+
+/// Initial delay before retrying to access the keyring
+const KEYRING_MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Reason accessing the keyring failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyringError {
+    /// The user denied access to the keyring
+    UserDenied,
+    /// There is no usable keyring
+    Unavailable,
+    /// The keyring is locked or not running yet, and may become available later
+    Transient,
+}
+
+impl KeyringError {
+    /// Classifies an error returned by the keyring
+    fn classify(error: &keyring::Error) -> Self {
+        // The platform errors only carry a description, so that is all there is to go by
+        let description = error.to_string().to_lowercase();
+        let transient = [
+            "locked",
+            "no such interface",
+            "serviceunknown",
+            "not activatable",
+        ]
+        .iter()
+        .any(|needle| description.contains(needle));
+        match error {
+            _ if transient => Self::Transient,
+            keyring::Error::NoStorageAccess(_) => Self::UserDenied,
+            _ => Self::Unavailable,
+        }
+    }
+}
+
+impl Display for KeyringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UserDenied => write!(f, "Access to the keyring was denied"),
+            Self::Unavailable => write!(f, "The keyring is not available"),
+            Self::Transient => write!(f, "The keyring is locked or not running"),
+        }
+    }
+}
+
+impl std::error::Error for KeyringError {}
+
+/// Purpose a key is derived from the root key for
+///
+/// Every key derived from the root key is listed here, so that two unrelated uses can't end up with the same key. The KDF context of a purpose must never change, or data encrypted with the derived key can no longer be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose<'a> {
+    /// Passphrase of the matrix-rust-sdk store
+    MatrixSdk,
+    /// Key of a mutable file, identified by its path relative to the data directory
+    File(&'a Path),
+    /// Purpose used by tests only
+    #[cfg(test)]
+    Test,
+}
+
+impl Purpose<'_> {
+    /// Returns the KDF context the key is derived with
+    fn context(&self) -> String {
+        match self {
+            Self::MatrixSdk => "rs.chir.rachat.crypto: matrix-rust-sdk".to_owned(),
+            Self::File(subdir) => format!(
+                "rs.chir.rachat.crypto.file: {}",
+                file_key_id(&crate::utils::path_to_bytes(subdir))
+            ),
+            #[cfg(test)]
+            Self::Test => "rs.chir.rachat.crypto: test".to_owned(),
+        }
+    }
+}
+
+/// Returns the name of a mutable file on disk, and the identifier its key is derived for
+fn file_key_id(subdir_bytes: &[u8]) -> String {
+    subdir_bytes.iter().map(|&c| c as char).collect()
+}
 
 /// 256 bit key derivation key. This is used as the IKM of a KDF.
+///
+/// Keys derived from it are cached. Clones share the cache.
 #[derive(Clone, Debug)]
-pub struct KDFSecretKey(Secret<[u8; 32]>);
+pub struct KDFSecretKey(Secret<[u8; 32]>, Arc<Mutex<DerivedKeyCache>>);
 
 impl KDFSecretKey {
     /// Generates a random new 256 key.
@@ -28,46 +129,97 @@ impl KDFSecretKey {
     }
 
     /// Creates a new secret key from 32 bytes
+    ///
+    /// The bytes are zeroized.
     #[must_use]
-    fn from_bytes(bytes: &mut [u8; 32]) -> Self {
-        let res = Self(Secret::new(*bytes));
+    pub(crate) fn from_bytes(bytes: &mut [u8; 32]) -> Self {
+        let res = Self(Secret::new(*bytes), Arc::default());
         bytes.zeroize();
         res
     }
 
-    /// Generates a KDF child key.
+    /// Generates a KDF child key for a purpose
+    #[must_use]
+    pub fn derive_subkey(&self, purpose: Purpose<'_>) -> Self {
+        let mut blake_key = self.derive_key(&purpose.context());
+        Self::from_bytes(&mut blake_key)
+    }
+
+    /// Generates a KDF child key from a free-form purpose.
     ///
     /// The purpose must be unique for each different subkey.
     ///
     /// The purpose may not include any secret information, including other keys.
     #[must_use]
+    #[deprecated(note = "add a variant to `Purpose` and use `derive_subkey` instead")]
     pub fn generate_kdf_subkey(&self, purpose: impl Display) -> Self {
         let context = format!("rs.chir.rachat.crypto: {purpose}");
-        let mut blake_key = blake3::derive_key(&context, self.0.expose_secret());
+        let mut blake_key = self.derive_key(&context);
         Self::from_bytes(&mut blake_key)
     }
 
+    /// Derives a key for a KDF context, using the cache if possible
+    ///
+    /// The returned key has to be zeroized by the caller.
+    fn derive_key(&self, context: &str) -> [u8; 32] {
+        self.1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_derive(context, || {
+                blake3::derive_key(context, self.0.expose_secret())
+            })
+    }
+
+    /// Removes all derived keys from the cache
+    pub fn clear_key_cache(&self) {
+        self.1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
     /// Generates a seeded CSPRNG with specified purpose.
     /// `
     /// From the same root key and subkey, it will generate the same CSPRNG every time.`
     #[must_use]
-    pub fn subkey_rng(&self, purpose: impl Display) -> impl CryptoRng + Rng {
-        let subkey = self.generate_kdf_subkey(purpose);
+    pub fn subkey_rng(&self, purpose: Purpose<'_>) -> impl CryptoRng + Rng {
+        let subkey = self.derive_subkey(purpose);
         rand_chacha::ChaChaRng::from_seed(*subkey.0.expose_secret())
     }
 
-    /// Generates an alphanumeric passphrase with specified purpose.
+    /// Generates a 32 character alphanumeric passphrase with specified purpose.
+    #[must_use]
+    pub fn subkey_passphrase(&self, purpose: Purpose<'_>) -> Secret<String> {
+        self.subkey_passphrase_with_len(purpose, 32)
+    }
+
+    /// Generates an alphanumeric passphrase of `len` characters with specified purpose.
+    ///
+    /// Passphrases of different lengths for the same purpose share their prefix.
     #[must_use]
-    pub fn subkey_passphrase(&self, purpose: impl Display) -> Secret<String> {
+    pub fn subkey_passphrase_with_len(&self, purpose: Purpose<'_>, len: usize) -> Secret<String> {
         let secret = self
             .subkey_rng(purpose)
             .sample_iter(&Alphanumeric)
-            .take(32)
+            .take(len)
             .map(char::from)
             .collect();
         Secret::new(secret)
     }
 
+    /// Checks whether `candidate` is the passphrase [`subkey_passphrase`](Self::subkey_passphrase) generates for `purpose`
+    ///
+    /// The comparison takes constant time, so it doesn't leak how much of the passphrase was guessed. Secrets must never be compared with `==`, which returns as soon as a byte differs.
+    #[must_use]
+    pub fn verify_passphrase(&self, purpose: Purpose<'_>, candidate: &str) -> bool {
+        let passphrase = self.subkey_passphrase(purpose);
+        passphrase
+            .expose_secret()
+            .as_bytes()
+            .ct_eq(candidate.as_bytes())
+            .into()
+    }
+
     /// Attempts to load the root key from the keyring from a specific profile.
     ///
     /// If it doesn’t exist, it will generate a new one and store it in the keyring.
@@ -82,30 +234,147 @@ impl KDFSecretKey {
     /// - The user has rejected access to the keyring.
     /// - There is some sort of IO error preventing the keyring from working.
     pub async fn load_from_keyring(profile: impl Display + Send) -> Result<Self> {
-        let profile = format!("{profile}");
-        let mut secret_json = tokio::task::spawn_blocking(move || -> Result<String> {
-            let entry = Entry::new("rs.chir.rachat", &format!("{profile}-key"))
-                .context("Formatting keyring entry")?;
-            match entry.get_password() {
-                Ok(entry) => Ok(entry),
-                Err(keyring::Error::NoEntry) => {
-                    let secret = Self::new();
-                    let secret_json = serde_json::to_string(secret.0.expose_secret())
-                        .context("serializing root KDF key")?;
-                    entry
-                        .set_password(&secret_json)
-                        .context("Setting KDF key in keyring")?;
-                    Ok(secret_json)
+        Self::load_from_keyring_with_retries(profile, DEFAULT_KEYRING_RETRIES).await
+    }
+
+    /// Attempts to load the root key from the keyring, retrying up to `max_retries` times if the keyring is temporarily unavailable.
+    ///
+    /// Retries back off exponentially, starting at 100ms. Errors that are not transient are returned immediately.
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the keyring fails. The error can be downcast to a [`KeyringError`] to tell apart why it failed.
+    pub async fn load_from_keyring_with_retries(
+        profile: impl Display + Send,
+        max_retries: u32,
+    ) -> Result<Self> {
+        Self::load_from_store(&Keyring, &profile.to_string(), max_retries).await
+    }
+
+    /// Loads the root key of a profile from the secret backend selected for it
+    ///
+    /// The file backend keeps the key in `config_dir`, protected by `passphrase`. The keyring backend ignores both. If the key doesn’t exist, it will generate a new one and store it.
+    ///
+    /// This is a shorthand for [`SecretBackend::open`] followed by [`load_from_store`](Self::load_from_store). Callers that need the secret store afterwards, for example to rotate the key, should do these steps themselves.
+    ///
+    /// # Errors
+    /// This function returns a [`PassphraseRequiredError`] if the file backend is selected, but no passphrase was given. It will return an error if accessing the backend fails.
+    pub async fn load(
+        profile: &str,
+        backend: SecretBackend,
+        config_dir: &Path,
+        passphrase: Option<&Secret<String>>,
+    ) -> Result<Self> {
+        let store = backend.open(config_dir, passphrase)?;
+        Self::load_from_store(store.as_ref(), profile, DEFAULT_KEYRING_RETRIES).await
+    }
+
+    /// Attempts to load the root key of a profile from a secret store, retrying up to `max_retries` times if the store is temporarily unavailable.
+    ///
+    /// If it doesn’t exist, it will generate a new one and store it.
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the store fails.
+    pub async fn load_from_store(
+        store: &dyn SecretStore,
+        profile: &str,
+        max_retries: u32,
+    ) -> Result<Self> {
+        let entry_name = Self::entry_name(profile);
+        let mut backoff = KEYRING_MIN_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let result = match Self::load_entry(store, &entry_name).await {
+                Ok(Some(key)) => Ok(key),
+                Ok(None) => {
+                    let key = Self::new();
+                    key.store_entry(store, &entry_name).await.map(|()| key)
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(key) => return Ok(key),
+                Err(e) => {
+                    let transient =
+                        e.downcast_ref::<KeyringError>() == Some(&KeyringError::Transient);
+                    if !transient || retries >= max_retries {
+                        return Err(e.wrap_err("Accessing KDF key in secret store"));
+                    }
+                    warn!("Keyring is not available yet, retrying in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    retries += 1;
                 }
-                Err(e) => Err(e).context("Accessing KDF key in keyring"),
             }
-        })
-        .await
-        .context("Blocking keychain access")??;
+        }
+    }
+
+    /// Returns the raw bytes of the key, for exporting it
+    pub(crate) fn expose_bytes(&self) -> &[u8; 32] {
+        self.0.expose_secret()
+    }
+
+    /// Returns whether a secret store holds a root key for a profile
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the store fails.
+    pub(crate) async fn exists_in_store(store: &dyn SecretStore, profile: &str) -> Result<bool> {
+        Ok(Self::load_entry(store, &Self::entry_name(profile))
+            .await?
+            .is_some())
+    }
+
+    /// Stores the key as the root key of a profile, replacing any existing root key
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the store fails.
+    pub(crate) async fn store_in(&self, store: &dyn SecretStore, profile: &str) -> Result<()> {
+        self.store_entry(store, &Self::entry_name(profile)).await
+    }
+
+    /// Deletes the root key of a profile from a secret store, if it exists
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the store fails.
+    pub(crate) async fn delete_from_store(store: &dyn SecretStore, profile: &str) -> Result<()> {
+        store.delete(&Self::entry_name(profile)).await
+    }
+
+    /// Returns the name of the keyring entry holding the root key of a profile
+    fn entry_name(profile: &str) -> String {
+        format!("{profile}-key")
+    }
+
+    /// Loads a key from a secret store entry, if it exists
+    async fn load_entry(store: &dyn SecretStore, entry_name: &str) -> Result<Option<Self>> {
+        let Some(secret_json) = store.get(entry_name).await? else {
+            return Ok(None);
+        };
+        let mut key =
+            serde_json::from_str(secret_json.expose_secret()).context("Deserializing key")?;
+        Ok(Some(Self::from_bytes(&mut key)))
+    }
+
+    /// Stores the key in a secret store entry, replacing any existing key
+    async fn store_entry(&self, store: &dyn SecretStore, entry_name: &str) -> Result<()> {
+        let secret_json =
+            serde_json::to_string(self.0.expose_secret()).context("Serializing key")?;
+        store.set(entry_name, Secret::new(secret_json)).await
+    }
 
-        let mut key = serde_json::from_str(&secret_json).context("Deserializing root key")?;
-        secret_json.zeroize();
-        Ok(Self::from_bytes(&mut key))
+    /// Checks that a secret store can be written to and read from, using a throwaway entry
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the store fails.
+    pub async fn check_secret_store(store: &dyn SecretStore) -> Result<()> {
+        let entry_name = format!("doctor-{:016x}", rand::random::<u64>());
+        let key = Self::new();
+        key.store_entry(store, &entry_name).await?;
+        let loaded = Self::load_entry(store, &entry_name).await;
+        store.delete(&entry_name).await?;
+        if loaded?.is_none() {
+            eyre::bail!("The secret store lost a freshly stored entry");
+        }
+        Ok(())
     }
 
     /// Returns a handle to a mutable data file
@@ -117,15 +386,13 @@ impl KDFSecretKey {
         subdir: impl AsRef<Path>,
     ) -> MutableFile {
         let subdir = subdir.as_ref();
-        let subdir_key_id = crate::utils::path_to_bytes(subdir)
-            .into_iter()
-            .map(|c| c as char)
-            .collect::<String>();
-        let context = format!("rs.chir.rachat.crypto.file: {subdir_key_id}");
-        let mut blake_key = blake3::derive_key(&context, self.0.expose_secret());
+        let subdir_bytes = crate::utils::path_to_bytes(subdir);
+        let subdir_key_id = file_key_id(&subdir_bytes);
+        let mut blake_key = self.derive_key(&Purpose::File(subdir).context());
         let res = MutableFile {
             path: data_path.as_ref().join(subdir_key_id),
             secret_key: chacha20poly1305::Key::from(blake_key),
+            aad: subdir_bytes,
         };
         blake_key.zeroize();
         res
@@ -140,15 +407,165 @@ impl Default for KDFSecretKey {
 
 #[cfg(test)]
 mod tests {
+    use std::fmt::Write;
+
+    use rand::RngCore;
     use secrecy::ExposeSecret;
 
+    use super::{KeyringError, Purpose};
+
     #[test]
     fn test_passphrase_stability() {
         let mut rk = [0u8; 32];
         let rk = super::KDFSecretKey::from_bytes(&mut rk);
         assert_eq!(
-            rk.subkey_passphrase("test").expose_secret(),
+            rk.subkey_passphrase(Purpose::Test).expose_secret(),
             "MH0ldlHJ0EyUjkxmOYfUutnktw7lTdYD"
         );
     }
+
+    #[test]
+    fn test_verify_passphrase() {
+        let mut rk = [0u8; 32];
+        let rk = super::KDFSecretKey::from_bytes(&mut rk);
+        assert!(rk.verify_passphrase(Purpose::Test, "MH0ldlHJ0EyUjkxmOYfUutnktw7lTdYD"));
+        assert!(!rk.verify_passphrase(Purpose::Test, "MH0ldlHJ0EyUjkxmOYfUutnktw7lTdYE"));
+        assert!(!rk.verify_passphrase(Purpose::MatrixSdk, "MH0ldlHJ0EyUjkxmOYfUutnktw7lTdYD"));
+        assert!(!rk.verify_passphrase(Purpose::Test, "MH0ldlHJ"));
+        assert!(!rk.verify_passphrase(Purpose::Test, ""));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_purposes_keep_their_derivation() {
+        let mut rk = [0u8; 32];
+        let rk = super::KDFSecretKey::from_bytes(&mut rk);
+        assert_eq!(
+            rk.derive_subkey(Purpose::MatrixSdk).expose_bytes(),
+            rk.generate_kdf_subkey("matrix-rust-sdk").expose_bytes()
+        );
+        assert_eq!(
+            rk.derive_subkey(Purpose::Test).expose_bytes(),
+            rk.generate_kdf_subkey("test").expose_bytes()
+        );
+        let file = rk.open_mutable_file("/data", "auth/login");
+        assert_eq!(
+            rk.derive_subkey(Purpose::File(std::path::Path::new("auth/login")))
+                .expose_bytes()
+                .as_slice(),
+            file.secret_key.as_slice()
+        );
+    }
+
+    /// Formats bytes as lowercase hex, for comparing them to test vectors
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().fold(String::new(), |mut out, byte| {
+            let _ = write!(out, "{byte:02x}");
+            out
+        })
+    }
+
+    #[test]
+    fn test_subkey_vectors() {
+        let mut rk = [0u8; 32];
+        let rk = super::KDFSecretKey::from_bytes(&mut rk);
+        assert_eq!(
+            hex(rk.derive_subkey(Purpose::MatrixSdk).expose_bytes()),
+            "e342ba5e63b678767ed31d00ab6e842ce5202a70aa31a89c9021b0d5db1cb88b"
+        );
+        assert_eq!(
+            hex(rk.derive_subkey(Purpose::Test).expose_bytes()),
+            "1893e1203dce6262c351721a355ef87ac3f78fb576e429cd39176b0c287367c7"
+        );
+        // The matrix store can't be re-keyed, so changing its passphrase loses the store
+        assert_eq!(
+            rk.subkey_passphrase(Purpose::MatrixSdk).expose_secret(),
+            "3sO6pPo5A6XljP2cXg9OnnYZCs4uhPOs"
+        );
+    }
+
+    // Paths are hashed in their platform representation, so the file keys differ on windows
+    #[cfg(unix)]
+    #[test]
+    fn test_file_key_vectors() {
+        let mut rk = [0u8; 32];
+        let rk = super::KDFSecretKey::from_bytes(&mut rk);
+        for (subdir, key) in [
+            (
+                "auth/login",
+                "d2f9512ff32a324429f85d72a641264dcd383245214a8da9b088d1a014aaf329",
+            ),
+            (
+                "keys/matrix-rust-sdk",
+                "926da18f0943e3367291a373323e5cceb9a95d37639c8d5d26a28f474831cfba",
+            ),
+            (
+                "media/stream",
+                "9e60dd438f84e59830ddf4f2d350e32cb5936bfff66db24f80d8f4d9222bbf5b",
+            ),
+            (
+                "päth/ü",
+                "1020b0bd1274550f2216da10db33b91dba9426c81168c6fa0a5b816f5a99b26e",
+            ),
+        ] {
+            assert_eq!(
+                hex(rk.open_mutable_file("/data", subdir).secret_key.as_slice()),
+                key,
+                "{subdir}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rng_vectors() {
+        let mut rk = [0u8; 32];
+        let rk = super::KDFSecretKey::from_bytes(&mut rk);
+        let mut output = [0u8; 32];
+        rk.subkey_rng(Purpose::Test).fill_bytes(&mut output);
+        assert_eq!(
+            hex(&output),
+            "2d8d453028777b1d7e8872d3b8c49a96a30d1e7784041896f21e84f9df434b1e"
+        );
+        rk.subkey_rng(Purpose::MatrixSdk).fill_bytes(&mut output);
+        assert_eq!(
+            hex(&output),
+            "37ca56dc8e96b8b29269fc38e9293ae8efc2aea6b0ffdc3e273e0fa3109ed3e6"
+        );
+    }
+
+    #[test]
+    fn test_keyring_error_classification() {
+        let locked = keyring::Error::NoStorageAccess(Box::new(std::io::Error::other(
+            "Collection is locked",
+        )));
+        assert_eq!(KeyringError::classify(&locked), KeyringError::Transient);
+        let denied = keyring::Error::NoStorageAccess(Box::new(std::io::Error::other(
+            "Prompt was dismissed",
+        )));
+        assert_eq!(KeyringError::classify(&denied), KeyringError::UserDenied);
+        let missing = keyring::Error::PlatformFailure(Box::new(std::io::Error::other(
+            "org.freedesktop.DBus.Error.ServiceUnknown",
+        )));
+        assert_eq!(KeyringError::classify(&missing), KeyringError::Transient);
+        assert_eq!(
+            KeyringError::classify(&keyring::Error::TooLong("service".to_owned(), 1)),
+            KeyringError::Unavailable
+        );
+    }
+
+    #[test]
+    fn test_passphrase_with_len_stability() {
+        let mut rk = [0u8; 32];
+        let rk = super::KDFSecretKey::from_bytes(&mut rk);
+        let long = rk.subkey_passphrase_with_len(Purpose::Test, 64);
+        assert_eq!(long.expose_secret().len(), 64);
+        assert!(long
+            .expose_secret()
+            .starts_with("MH0ldlHJ0EyUjkxmOYfUutnktw7lTdYD"));
+        assert_eq!(
+            rk.subkey_passphrase_with_len(Purpose::Test, 8)
+                .expose_secret(),
+            "MH0ldlHJ"
+        );
+    }
 }
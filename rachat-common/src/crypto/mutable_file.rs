@@ -2,23 +2,142 @@
 //!
 //! Mutable files are encrypted using xchacha20-poly1305, with a key generated from the root key.
 //!
-//! The encrypted file starts with a 24 byte nonce, followed by the encrypted data and then the 16 byte authentication tag.
+//! The encrypted file starts with the 8 byte header `rachatF1`, followed by a 24 byte nonce, the encrypted data and then the 16 byte authentication tag.
 //!
 //! Every write to the file will generate a new nonce, to prevent finding out the difference between two consecutive writes.
 //!
+//! The logical path of the file is bound into the ciphertext as associated data, so a file that is moved to a different slot fails to decrypt. Files written before this was introduced have empty associated data.
 //!
+//! Files without the header predate both the header and the associated data. They can still be read through [`MutableFile::read`], which rewrites them in the current format, so that support for them can be dropped once existing profiles have been migrated.
+//!
+//! Writes go to a temporary file next to the target (`<name>.tmp-<random>`), which is synced to disk and then renamed over the target. Readers therefore always see either the previous or the new contents, even if the process crashes mid-write.
+//!
+//! # Streamed files
+//!
+//! Large files can instead be written and read in chunks using [`MutableFile::open_write_stream`] and [`MutableFile::open_read_stream`]. These use the STREAM construction (big-endian 32 bit counter variant) and have a different on-disk layout:
+//!
+//! - A 19 byte random nonce prefix. The remaining 5 bytes of each chunk nonce are the chunk counter and a flag marking the last chunk.
+//! - Any number of 64 KiB plaintext chunks, each encrypted into 64 KiB of ciphertext followed by its 16 byte authentication tag.
+//! - The last chunk, holding up to 64 KiB of plaintext followed by its authentication tag. It is always present, even if it is empty.
+//!
+//! Truncating, reordering or appending chunks causes decryption to fail. The two layouts are not compatible, so a file has to be consistently accessed through either the buffered or the streaming API.
+//!
+//! # Errors
+//!
+//! All operations return a [`MutableFileError`], so that callers can tell a failed decryption, which means that the file has been tampered with or was written with a different key, apart from I/O errors.
 
 use chacha20poly1305::{
-    aead::{Aead, Payload},
+    aead::{
+        stream::{DecryptorBE32, EncryptorBE32, Nonce, StreamBE32},
+        Aead, Payload,
+    },
     AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
 };
-use eyre::{Context, Result};
-use rand::thread_rng;
-use std::path::PathBuf;
+use educe::Educe;
+use rand::{thread_rng, RngCore};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs,
     io::{AsyncReadExt, AsyncWriteExt},
 };
+use tracing::{debug, error, warn};
+
+/// Header at the start of buffered files written in the current format
+const FILE_HEADER: &[u8; 8] = b"rachatF1";
+
+/// Marker between the file name and the random suffix of temporary files
+const TEMP_FILE_MARKER: &str = ".tmp-";
+
+/// Size of a plaintext chunk in a streamed file
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of an encrypted chunk in a streamed file, including the authentication tag
+const STREAM_CIPHERTEXT_CHUNK_SIZE: usize = STREAM_CHUNK_SIZE + 16;
+
+/// Nonce prefix stored at the start of a streamed file
+type StreamNoncePrefix = Nonce<XChaCha20Poly1305, StreamBE32<XChaCha20Poly1305>>;
+
+/// Error returned by operations on mutable files
+#[derive(Debug)]
+pub enum MutableFileError {
+    /// The file or one of its parent directories does not exist
+    NotFound {
+        /// Path of the file
+        path: PathBuf,
+    },
+    /// Reading or writing the file failed
+    Io {
+        /// What was being done when the error occurred
+        action: &'static str,
+        /// Path of the file
+        path: PathBuf,
+        /// The underlying error
+        source: std::io::Error,
+    },
+    /// The data could not be encrypted
+    Encrypt {
+        /// Path of the file
+        path: PathBuf,
+    },
+    /// The file could not be decrypted
+    ///
+    /// This means that the file has been tampered with or truncated, that it was written with a different key or associated data, or that it was written through the other of the buffered and the streaming API.
+    Decrypt {
+        /// Path of the file
+        path: PathBuf,
+    },
+}
+
+impl MutableFileError {
+    /// Wraps an I/O error, keeping missing files apart from other errors
+    fn io(action: &'static str, path: &Path, source: std::io::Error) -> Self {
+        if source.kind() == std::io::ErrorKind::NotFound {
+            Self::NotFound {
+                path: path.to_owned(),
+            }
+        } else {
+            Self::Io {
+                action,
+                path: path.to_owned(),
+                source,
+            }
+        }
+    }
+
+    /// Returns the path of the file the error occurred on
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::NotFound { path }
+            | Self::Io { path, .. }
+            | Self::Encrypt { path }
+            | Self::Decrypt { path } => path,
+        }
+    }
+}
+
+impl fmt::Display for MutableFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { path } => write!(f, "{} does not exist", path.display()),
+            Self::Io { action, path, .. } => write!(f, "{action} {}", path.display()),
+            Self::Encrypt { path } => write!(f, "Encrypting data for {}", path.display()),
+            Self::Decrypt { path } => write!(f, "Decryption of file {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for MutableFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 /// Reference to a mutable data file
 #[derive(Clone, Debug)]
@@ -27,97 +146,770 @@ pub struct MutableFile {
     pub(super) path: PathBuf,
     /// The encryption key for the file
     pub(super) secret_key: chacha20poly1305::Key,
+    /// Associated data bound to the file by default
+    pub(super) aad: Vec<u8>,
 }
 
 impl MutableFile {
     /// Writes data to the file, overwriting any existing data.
     ///
+    /// The logical path of the file is used as the associated data.
+    ///
     /// # Errors
     /// This function will return an error if writing to the file fails.
-    pub async fn write(&self, data: impl AsRef<[u8]> + Send) -> Result<()> {
-        if let Some(path) = self.path.parent() {
-            fs::create_dir_all(path).await.with_context(|| {
-                format!(
-                    "Creating parent directory of {} ({})",
-                    self.path.display(),
-                    path.display()
-                )
-            })?;
-        }
-        let data = data.as_ref();
+    pub async fn write(&self, data: impl AsRef<[u8]> + Send) -> Result<(), MutableFileError> {
+        self.write_with_aad(data, &self.aad).await
+    }
+
+    /// Writes data to the file with caller-supplied associated data, overwriting any existing data.
+    ///
+    /// The same associated data has to be passed to [`read_with_aad`](Self::read_with_aad) to decrypt the file again.
+    ///
+    /// # Errors
+    /// This function will return an error if writing to the file fails.
+    pub async fn write_with_aad(
+        &self,
+        data: impl AsRef<[u8]> + Send,
+        aad: impl AsRef<[u8]> + Send,
+    ) -> Result<(), MutableFileError> {
+        self.create_parent_dir().await?;
+        let payload = Payload {
+            aad: aad.as_ref(),
+            msg: data.as_ref(),
+        };
 
         let cipher = XChaCha20Poly1305::new(&self.secret_key);
         let nonce = XChaCha20Poly1305::generate_nonce(thread_rng());
         let payload = cipher
-            .encrypt(&nonce, data)
-            .with_context(|| format!("Encrypting data for {}", self.path.display()))?;
+            .encrypt(&nonce, payload)
+            .map_err(|_| MutableFileError::Encrypt {
+                path: self.path.clone(),
+            })?;
 
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(&self.path)
+        let mut file = TempFile::create(&self.path).await?;
+        file.write_all(FILE_HEADER)
             .await
-            .with_context(|| format!("Creating and opening file {}", self.path.display()))?;
-
+            .map_err(|e| MutableFileError::io("Writing header for", &self.path, e))?;
         file.write_all(&nonce)
             .await
-            .with_context(|| format!("writing nonce for {}", self.path.display()))?;
+            .map_err(|e| MutableFileError::io("Writing nonce for", &self.path, e))?;
         file.write_all(&payload)
             .await
-            .with_context(|| format!("writing ciphertext for {}", self.path.display()))?;
-
-        Ok(())
+            .map_err(|e| MutableFileError::io("Writing ciphertext for", &self.path, e))?;
+        file.persist(&self.path).await
     }
 
     /// Reads data from the file
     ///
+    /// The logical path of the file is used as the associated data. Files in the legacy format, which may have been written without associated data, are accepted as well and are rewritten in the current format.
+    ///
+    /// Returns `None` if the file does not exist.
+    ///
     /// # Errors
-    /// This function will return an error if reading from the file fails.
-    pub async fn read(&self) -> Result<Option<Vec<u8>>> {
-        let mut file = match fs::OpenOptions::new().read(true).open(&self.path).await {
-            Ok(file) => file,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    return Ok(None);
-                }
-                Err(e).with_context(|| format!("Opening file {}", self.path.display()))?;
-                unreachable!();
-            }
+    /// This function will return an error if reading from the file fails, or [`MutableFileError::Decrypt`] if it could not be decrypted.
+    pub async fn read(&self) -> Result<Option<Vec<u8>>, MutableFileError> {
+        let Some(raw) = self.read_raw().await? else {
+            return Ok(None);
         };
-        let cipher = XChaCha20Poly1305::new(&self.secret_key);
-        let mut nonce = XNonce::default();
-        file.read_exact(&mut nonce)
-            .await
-            .with_context(|| format!("Reading nonce of file {}", self.path.display()))?;
-        let mut payload = Vec::new();
-        file.read_to_end(&mut payload)
+        if !raw.legacy {
+            return self
+                .decrypt(&raw.nonce, &raw.ciphertext, &self.aad)
+                .map(Some);
+        }
+        let data = self
+            .decrypt(&raw.nonce, &raw.ciphertext, &self.aad)
+            .or_else(|e| {
+                self.decrypt(&raw.nonce, &raw.ciphertext, &[])
+                    .map_err(|_| e)
+            })?;
+        self.migrate(&data, &self.aad).await;
+        Ok(Some(data))
+    }
+
+    /// Reads data from the file with caller-supplied associated data
+    ///
+    /// Files in the legacy format are rewritten in the current format.
+    ///
+    /// # Errors
+    /// This function will return an error if reading from the file fails, or [`MutableFileError::Decrypt`] if the associated data does not match the one used for writing.
+    pub async fn read_with_aad(
+        &self,
+        aad: impl AsRef<[u8]> + Send,
+    ) -> Result<Option<Vec<u8>>, MutableFileError> {
+        let Some(raw) = self.read_raw().await? else {
+            return Ok(None);
+        };
+        let data = self.decrypt(&raw.nonce, &raw.ciphertext, aad.as_ref())?;
+        if raw.legacy {
+            self.migrate(&data, aad.as_ref()).await;
+        }
+        Ok(Some(data))
+    }
+
+    /// Rewrites a file that was read in the legacy format in the current format
+    ///
+    /// Failing to do so is not an error, as the data has been read successfully. The migration is attempted again on the next read.
+    async fn migrate(&self, data: &[u8], aad: &[u8]) {
+        debug!(
+            "Migrating {} to the current file format",
+            self.path.display()
+        );
+        if let Err(e) = self.write_with_aad(data, aad).await {
+            warn!("Failed to migrate {}: {e:#}", self.path.display());
+        }
+    }
+
+    /// Reads the header, the nonce and the ciphertext of the file
+    async fn read_raw(&self) -> Result<Option<RawFile>, MutableFileError> {
+        let Some(mut file) = self.open_for_reading().await? else {
+            return Ok(None);
+        };
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
             .await
-            .with_context(|| format!("Reading ciphertext of file {}", self.path.display()))?;
+            .map_err(|e| MutableFileError::io("Reading file", &self.path, e))?;
+
+        let (legacy, rest) = contents
+            .strip_prefix(FILE_HEADER)
+            .map_or((true, &contents[..]), |rest| (false, rest));
+        if rest.len() < XNonce::default().len() {
+            return Err(MutableFileError::io(
+                "Reading nonce of file",
+                &self.path,
+                std::io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        let (nonce, ciphertext) = rest.split_at(XNonce::default().len());
+        Ok(Some(RawFile {
+            legacy,
+            nonce: *XNonce::from_slice(nonce),
+            ciphertext: ciphertext.to_vec(),
+        }))
+    }
+
+    /// Opens the file for reading, returning `None` if it does not exist
+    async fn open_for_reading(&self) -> Result<Option<fs::File>, MutableFileError> {
+        match fs::OpenOptions::new().read(true).open(&self.path).await {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(MutableFileError::io("Opening file", &self.path, e)),
+        }
+    }
+
+    /// Decrypts the contents of the file
+    fn decrypt(
+        &self,
+        nonce: &XNonce,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, MutableFileError> {
+        let cipher = XChaCha20Poly1305::new(&self.secret_key);
         let payload = Payload {
-            aad: &[],
-            msg: &payload,
+            aad,
+            msg: ciphertext,
+        };
+        cipher
+            .decrypt(nonce, payload)
+            .map_err(|_| MutableFileError::Decrypt {
+                path: self.path.clone(),
+            })
+    }
+
+    /// Opens the file for streamed writing, overwriting any existing data.
+    ///
+    /// The data is encrypted in fixed-size chunks, so memory use stays bounded no matter how large the file gets. The existing contents are only replaced once [`MutableFileWriter::finish`] has been called.
+    ///
+    /// # Errors
+    /// This function will return an error if creating the file fails.
+    pub async fn open_write_stream(&self) -> Result<MutableFileWriter, MutableFileError> {
+        self.create_parent_dir().await?;
+
+        let mut nonce_prefix = StreamNoncePrefix::default();
+        thread_rng().fill_bytes(&mut nonce_prefix);
+
+        let mut file = TempFile::create(&self.path).await?;
+        file.write_all(&nonce_prefix)
+            .await
+            .map_err(|e| MutableFileError::io("Writing nonce prefix for", &self.path, e))?;
+
+        Ok(MutableFileWriter {
+            path: self.path.clone(),
+            aad: self.aad.clone(),
+            file,
+            encryptor: EncryptorBE32::new(&self.secret_key, &nonce_prefix),
+            buffer: Vec::with_capacity(STREAM_CHUNK_SIZE),
+        })
+    }
+
+    /// Opens the file for streamed reading
+    ///
+    /// Returns `None` if the file does not exist.
+    ///
+    /// # Errors
+    /// This function will return an error if opening the file or reading its header fails.
+    pub async fn open_read_stream(&self) -> Result<Option<MutableFileReader>, MutableFileError> {
+        let Some(mut file) = self.open_for_reading().await? else {
+            return Ok(None);
         };
-        Ok(Some(cipher.decrypt(&nonce, payload).with_context(
-            || format!("Decryption of file {}", self.path.display()),
-        )?))
+        let mut nonce_prefix = StreamNoncePrefix::default();
+        file.read_exact(&mut nonce_prefix)
+            .await
+            .map_err(|e| MutableFileError::io("Reading nonce prefix of file", &self.path, e))?;
+
+        Ok(Some(MutableFileReader {
+            path: self.path.clone(),
+            aad: self.aad.clone(),
+            file,
+            decryptor: Some(DecryptorBE32::new(&self.secret_key, &nonce_prefix)),
+            buffer: Vec::with_capacity(STREAM_CIPHERTEXT_CHUNK_SIZE + 1),
+        }))
+    }
+
+    /// Creates the parent directory of the file
+    async fn create_parent_dir(&self) -> Result<(), MutableFileError> {
+        if let Some(path) = self.path.parent() {
+            fs::create_dir_all(path)
+                .await
+                .map_err(|e| MutableFileError::io("Creating parent directory of", &self.path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the file exists
+    ///
+    /// # Errors
+    /// This function will return an error if the existence of the file can't be determined.
+    pub(crate) async fn exists(&self) -> Result<bool, MutableFileError> {
+        fs::try_exists(&self.path)
+            .await
+            .map_err(|e| MutableFileError::io("Checking the existence of", &self.path, e))
     }
 
     /// Deletes the file if it exists
     ///
     /// # Errors
     /// This function will return an error if deleting the file fails.
-    pub(crate) async fn delete(&self) -> Result<()> {
+    pub(crate) async fn delete(&self) -> Result<(), MutableFileError> {
         match fs::remove_file(&self.path).await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    return Ok(());
-                }
-                Err(e)
-                    .with_context(|| format!("Deleting encrypted file {}.", self.path.display()))?;
-                Ok(())
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(MutableFileError::io(
+                "Deleting encrypted file",
+                &self.path,
+                e,
+            )),
+        }
+    }
+}
+
+/// Contents of a buffered file, split into its parts
+struct RawFile {
+    /// Whether the file lacks the header, meaning that it was written before the header was introduced
+    legacy: bool,
+    /// Nonce the file was encrypted with
+    nonce: XNonce,
+    /// The encrypted data, followed by the authentication tag
+    ciphertext: Vec<u8>,
+}
+
+/// Returns a fresh temporary path next to `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(
+        "{TEMP_FILE_MARKER}{:016x}",
+        thread_rng().next_u64()
+    ));
+    path.with_file_name(file_name)
+}
+
+/// Returns whether a file name is one of a temporary file created by [`temp_path`]
+fn is_temp_file_name(file_name: &str) -> bool {
+    file_name
+        .rsplit_once(TEMP_FILE_MARKER)
+        .is_some_and(|(_, suffix)| {
+            suffix.len() == 16 && suffix.bytes().all(|b| b.is_ascii_hexdigit())
+        })
+}
+
+/// Writes a file atomically
+///
+/// The contents are written to a temporary file next to `path`, which is synced to disk and then renamed over `path`. The parent directory is created if it doesn't exist.
+///
+/// # Errors
+/// This function will return an error if creating, writing or renaming the temporary file fails. The temporary file is removed again in that case.
+pub(crate) async fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), MutableFileError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|e| MutableFileError::io("Creating parent directory of", path, e))?;
+    }
+    let mut file = TempFile::create(path).await?;
+    file.write_all(contents)
+        .await
+        .map_err(|e| MutableFileError::io("Writing", path, e))?;
+    file.persist(path).await
+}
+
+/// Removes temporary files that were left behind by interrupted writes
+///
+/// This has to be called before any file below `dir` is written, as it can't tell apart files that are still being written.
+///
+/// # Errors
+/// This function will return an error if listing the directory or removing a file fails.
+pub(crate) async fn remove_stale_temp_files(dir: &Path) -> Result<(), MutableFileError> {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(MutableFileError::io("Listing", &dir, e)),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| MutableFileError::io("Listing", &dir, e))?
+        {
+            let path = entry.path();
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| MutableFileError::io("Checking the type of", &path, e))?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if entry.file_name().to_str().is_some_and(is_temp_file_name) {
+                debug!("Removing stale temporary file {}", path.display());
+                fs::remove_file(&path)
+                    .await
+                    .map_err(|e| MutableFileError::io("Removing stale temporary file", &path, e))?;
             }
         }
     }
+    Ok(())
+}
+
+/// Temporary file that replaces a target file once it is fully written
+///
+/// The temporary file is removed when this is dropped without being persisted, so that failed or abandoned writes don't leave it behind.
+#[derive(Debug)]
+struct TempFile {
+    /// The open file
+    ///
+    /// This is declared first so that it is closed before the file is removed, which is required on Windows.
+    file: fs::File,
+    /// Path of the temporary file, which removes it when dropped
+    path: TempPath,
+}
+
+impl TempFile {
+    /// Creates a new temporary file next to `target`
+    async fn create(target: &Path) -> Result<Self, MutableFileError> {
+        let path = temp_path(target);
+        let file = fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .await
+            .map_err(|e| MutableFileError::io("Creating and opening file", &path, e))?;
+        Ok(Self {
+            file,
+            path: TempPath(path),
+        })
+    }
+
+    /// Appends data to the temporary file
+    async fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data).await
+    }
+
+    /// Syncs the temporary file to disk and moves it over `target`
+    ///
+    /// The parent directory is synced as well, so that the rename survives a crash.
+    async fn persist(self, target: &Path) -> Result<(), MutableFileError> {
+        // Bound in this order so that the file is closed before the path is removed on errors
+        let Self { path, mut file } = self;
+        file.flush()
+            .await
+            .map_err(|e| MutableFileError::io("Flushing", &path.0, e))?;
+        file.sync_all()
+            .await
+            .map_err(|e| MutableFileError::io("Syncing", &path.0, e))?;
+        // The file has to be closed before it can be renamed on Windows
+        drop(file);
+        fs::rename(&path.0, target)
+            .await
+            .map_err(|e| MutableFileError::io("Moving the temporary file to", target, e))?;
+        // The temporary file no longer exists, so it must not be removed on drop
+        path.disarm();
+        sync_parent_dir(target).await
+    }
+}
+
+/// Path of a temporary file, which is removed when this is dropped
+#[derive(Debug)]
+struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Keeps the file from being removed
+    fn disarm(mut self) {
+        self.0 = PathBuf::new();
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.as_os_str().is_empty() {
+            return;
+        }
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove {}: {e:#?}", self.0.display()),
+        }
+    }
+}
+
+/// Syncs the directory containing `path` to disk, so that a rename into it is durable
+#[cfg(unix)]
+async fn sync_parent_dir(path: &Path) -> Result<(), MutableFileError> {
+    let Some(parent) = path.parent() else {
+        return Ok(());
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    fs::File::open(parent)
+        .await
+        .map_err(|e| MutableFileError::io("Opening parent directory of", path, e))?
+        .sync_all()
+        .await
+        .map_err(|e| MutableFileError::io("Syncing parent directory of", path, e))
+}
+
+/// Syncs the directory containing `path` to disk, so that a rename into it is durable
+///
+/// Directories can't be opened as files on other platforms, where renames are journaled by the file system instead.
+#[cfg(not(unix))]
+#[allow(clippy::unused_async)] // Matches the signature of the unix version
+async fn sync_parent_dir(_path: &Path) -> Result<(), MutableFileError> {
+    Ok(())
+}
+
+/// Streaming writer for a mutable file
+///
+/// Dropping the writer without calling [`finish`](Self::finish) leaves the previous contents of the file in place, and removes the partially written data.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct MutableFileWriter {
+    /// Path to the file
+    path: PathBuf,
+    /// Associated data bound to every chunk
+    aad: Vec<u8>,
+    /// The temporary file that is being written
+    file: TempFile,
+    /// Chunk encryptor
+    #[educe(Debug(ignore))]
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    /// Plaintext that has not been encrypted yet
+    #[educe(Debug(ignore))]
+    buffer: Vec<u8>,
+}
+
+impl MutableFileWriter {
+    /// Appends data to the file
+    ///
+    /// # Errors
+    /// This function will return an error if encrypting or writing a chunk fails.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), MutableFileError> {
+        while !data.is_empty() {
+            // A full chunk is only written once more data arrives, as the last chunk has to be encrypted by `finish`
+            if self.buffer.len() == STREAM_CHUNK_SIZE {
+                let ciphertext = self
+                    .encryptor
+                    .encrypt_next(Payload {
+                        aad: &self.aad,
+                        msg: &self.buffer,
+                    })
+                    .map_err(|_| MutableFileError::Encrypt {
+                        path: self.path.clone(),
+                    })?;
+                self.file
+                    .write_all(&ciphertext)
+                    .await
+                    .map_err(|e| MutableFileError::io("Writing chunk for", &self.path, e))?;
+                self.buffer.clear();
+            }
+            let len = (STREAM_CHUNK_SIZE - self.buffer.len()).min(data.len());
+            let (head, tail) = data.split_at(len);
+            self.buffer.extend_from_slice(head);
+            data = tail;
+        }
+        Ok(())
+    }
+
+    /// Writes the last chunk and replaces the file with the newly written data
+    ///
+    /// # Errors
+    /// This function will return an error if encrypting or writing the last chunk fails, or if the file could not be replaced.
+    pub async fn finish(self) -> Result<(), MutableFileError> {
+        let Self {
+            path,
+            aad,
+            mut file,
+            encryptor,
+            buffer,
+        } = self;
+        let ciphertext = encryptor
+            .encrypt_last(Payload {
+                aad: &aad,
+                msg: &buffer,
+            })
+            .map_err(|_| MutableFileError::Encrypt { path: path.clone() })?;
+        file.write_all(&ciphertext)
+            .await
+            .map_err(|e| MutableFileError::io("Writing last chunk for", &path, e))?;
+        file.persist(&path).await
+    }
+}
+
+/// Streaming reader for a mutable file
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct MutableFileReader {
+    /// Path to the file
+    path: PathBuf,
+    /// Associated data bound to every chunk
+    aad: Vec<u8>,
+    /// The open file
+    file: fs::File,
+    /// Chunk decryptor, `None` once the last chunk has been read
+    #[educe(Debug(ignore))]
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    /// Ciphertext that has been read but not decrypted yet
+    #[educe(Debug(ignore))]
+    buffer: Vec<u8>,
+}
+
+impl MutableFileReader {
+    /// Reads and decrypts the next chunk of the file
+    ///
+    /// Returns `None` once the whole file has been read.
+    ///
+    /// # Errors
+    /// This function will return an error if reading the file fails, or [`MutableFileError::Decrypt`] if a chunk fails to decrypt. The latter means that the file has been truncated or tampered with.
+    pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, MutableFileError> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            return Ok(None);
+        };
+
+        // Read one byte past a full chunk to find out whether it is the last one
+        let wanted = STREAM_CIPHERTEXT_CHUNK_SIZE + 1;
+        if self.buffer.len() < wanted {
+            let missing = (wanted - self.buffer.len()) as u64;
+            (&mut self.file)
+                .take(missing)
+                .read_to_end(&mut self.buffer)
+                .await
+                .map_err(|e| MutableFileError::io("Reading chunk of file", &self.path, e))?;
+        }
+
+        if self.buffer.len() == wanted {
+            let rest = self.buffer.split_off(STREAM_CIPHERTEXT_CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            let plaintext = decryptor
+                .decrypt_next(Payload {
+                    aad: &self.aad,
+                    msg: &chunk,
+                })
+                .map_err(|_| MutableFileError::Decrypt {
+                    path: self.path.clone(),
+                })?;
+            Ok(Some(plaintext))
+        } else {
+            let Some(decryptor) = self.decryptor.take() else {
+                return Ok(None);
+            };
+            let plaintext = decryptor
+                .decrypt_last(Payload {
+                    aad: &self.aad,
+                    msg: &self.buffer,
+                })
+                .map_err(|_| MutableFileError::Decrypt {
+                    path: self.path.clone(),
+                })?;
+            self.buffer.clear();
+            Ok(Some(plaintext))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chacha20poly1305::{aead::Aead, AeadCore, KeyInit, XChaCha20Poly1305};
+    use rand::RngCore;
+
+    use super::{
+        is_temp_file_name, remove_stale_temp_files, temp_path, MutableFile, MutableFileError,
+        FILE_HEADER, STREAM_CHUNK_SIZE,
+    };
+    use crate::{crypto::KDFSecretKey, test_utils::TempDir};
+
+    /// Writes a file in the legacy format, without header and with the given associated data
+    async fn write_legacy(file: &MutableFile, data: &[u8], aad: &[u8]) -> eyre::Result<()> {
+        let nonce = XChaCha20Poly1305::generate_nonce(rand::thread_rng());
+        let ciphertext = XChaCha20Poly1305::new(&file.secret_key)
+            .encrypt(&nonce, chacha20poly1305::aead::Payload { msg: data, aad })
+            .map_err(|_| eyre::eyre!("encryption failed"))?;
+        if let Some(parent) = file.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&file.path, [&nonce[..], &ciphertext].concat()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_round_trip() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let root_key = KDFSecretKey::new();
+
+        for len in [0, 1, STREAM_CHUNK_SIZE, 2 * STREAM_CHUNK_SIZE + 123] {
+            let mut data = vec![0; len];
+            rand::thread_rng().fill_bytes(&mut data);
+            let file = root_key.open_mutable_file(&data_dir, format!("stream-{len}"));
+
+            let mut writer = file.open_write_stream().await?;
+            for part in data.chunks(1000) {
+                writer.write(part).await?;
+            }
+            writer.finish().await?;
+
+            let mut reader = file
+                .open_read_stream()
+                .await?
+                .ok_or_else(|| eyre::eyre!("missing file"))?;
+            let mut read_back = Vec::new();
+            while let Some(chunk) = reader.read_chunk().await? {
+                read_back.extend_from_slice(&chunk);
+            }
+            assert_eq!(read_back, data);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_write_keeps_old_contents() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let file = KDFSecretKey::new().open_mutable_file(&data_dir, "atomic");
+        let dir_entries = || -> eyre::Result<Vec<_>> {
+            let mut entries = std::fs::read_dir(&data_dir)?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            entries.sort();
+            Ok(entries)
+        };
+
+        file.write(b"old contents").await?;
+
+        // Simulate a crash halfway through a write
+        tokio::fs::write(temp_path(&file.path), b"partial").await?;
+        assert_eq!(file.read().await?.as_deref(), Some(&b"old contents"[..]));
+        assert_eq!(dir_entries()?.len(), 2);
+        remove_stale_temp_files(&data_dir).await?;
+        assert_eq!(dir_entries()?, ["atomic"]);
+
+        // Unfinished stream writes don't replace the file either, and are cleaned up
+        let mut writer = file.open_write_stream().await?;
+        writer.write(b"new contents").await?;
+        assert_eq!(dir_entries()?.len(), 2);
+        drop(writer);
+        assert_eq!(file.read().await?.as_deref(), Some(&b"old contents"[..]));
+        assert_eq!(dir_entries()?, ["atomic"]);
+
+        file.write(b"new contents").await?;
+        assert_eq!(file.read().await?.as_deref(), Some(&b"new contents"[..]));
+        assert_eq!(dir_entries()?, ["atomic"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_file_names() {
+        let path = temp_path(Path::new("dir/file"));
+        let file_name = path.file_name().and_then(|name| name.to_str());
+        assert!(file_name.is_some_and(is_temp_file_name), "{path:?}");
+        assert!(!is_temp_file_name("file"));
+        assert!(!is_temp_file_name("file.tmp-notahexnumber!"));
+    }
+
+    #[tokio::test]
+    async fn test_aad_mismatch_fails() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let file = KDFSecretKey::new().open_mutable_file(&data_dir, "aad");
+
+        file.write_with_aad(b"secret", b"slot a").await?;
+        assert_eq!(
+            file.read_with_aad(b"slot a").await?.as_deref(),
+            Some(&b"secret"[..])
+        );
+        assert!(file.read_with_aad(b"slot b").await.is_err());
+        assert!(file.read().await.is_err());
+
+        file.write_with_aad(b"unbound", b"").await?;
+        assert!(file.read().await.is_err());
+        assert_eq!(
+            file.read_with_aad(b"").await?.as_deref(),
+            Some(&b"unbound"[..])
+        );
+
+        file.write(b"bound").await?;
+        assert!(file.read_with_aad(b"").await.is_err());
+        assert_eq!(file.read().await?.as_deref(), Some(&b"bound"[..]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_files_are_migrated() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let file = KDFSecretKey::new().open_mutable_file(&data_dir, "legacy");
+
+        for aad in [&b""[..], &file.aad] {
+            write_legacy(&file, b"legacy", aad).await?;
+            assert_eq!(file.read().await?.as_deref(), Some(&b"legacy"[..]));
+
+            // The file is now in the current format, bound to its path
+            let contents = tokio::fs::read(&file.path).await?;
+            assert!(contents.starts_with(FILE_HEADER));
+            assert!(file.read_with_aad(b"").await.is_err());
+            assert_eq!(file.read().await?.as_deref(), Some(&b"legacy"[..]));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tampering_is_a_decryption_error() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let file = KDFSecretKey::new().open_mutable_file(&data_dir, "tampered");
+
+        assert_eq!(file.read().await?, None);
+        file.write(b"secret").await?;
+        let mut contents = tokio::fs::read(&file.path).await?;
+        if let Some(byte) = contents.last_mut() {
+            *byte ^= 1;
+        }
+        tokio::fs::write(&file.path, contents).await?;
+        assert!(matches!(
+            file.read().await,
+            Err(MutableFileError::Decrypt { path }) if path == file.path
+        ));
+
+        // A file too short to hold a nonce is an I/O error, not a decryption error
+        tokio::fs::write(&file.path, b"short").await?;
+        assert!(matches!(
+            file.read().await,
+            Err(MutableFileError::Io { .. })
+        ));
+        Ok(())
+    }
 }
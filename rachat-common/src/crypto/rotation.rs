@@ -0,0 +1,301 @@
+//! Root key rotation
+//!
+//! Rotating the root key re-encrypts every mutable file of a profile. The new key is first stored in a separate entry of the secret store. It only replaces the root key once all files have been re-encrypted, so an interrupted rotation can be resumed by running it again.
+
+use std::path::Path;
+
+use eyre::{Context, Result};
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+use super::{
+    mutable_file::{MutableFile, MutableFileError},
+    KDFSecretKey, SecretStore,
+};
+
+impl KDFSecretKey {
+    /// Returns the name of the secret store entry holding a not yet completed rotation
+    fn pending_entry_name(profile: &str) -> String {
+        format!("{profile}-key-next")
+    }
+
+    /// Schedules a rotation of the root key of a profile
+    ///
+    /// The rotation itself is performed by [`rotate`](Self::rotate). Scheduling a rotation twice has no further effect.
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the secret store fails.
+    pub async fn schedule_rotation(store: &dyn SecretStore, profile: &str) -> Result<()> {
+        let entry_name = Self::pending_entry_name(profile);
+        if Self::load_entry(store, &entry_name).await?.is_none() {
+            Self::new().store_entry(store, &entry_name).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a rotation of the root key of a profile is scheduled or was interrupted
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the secret store fails.
+    pub async fn has_pending_rotation(store: &dyn SecretStore, profile: &str) -> Result<bool> {
+        Ok(Self::load_entry(store, &Self::pending_entry_name(profile))
+            .await?
+            .is_some())
+    }
+
+    /// Rotates the root key of a profile, returning the new root key
+    ///
+    /// All mutable files below `data_path` are re-encrypted with the new root key. Paths in `exclude` are relative to `data_path` and are skipped entirely, which is useful for large files that are known not to be mutable files.
+    ///
+    /// Only data encrypted with keys derived by [`open_mutable_file`](Self::open_mutable_file) is migrated. Secrets derived from the root key in other ways, like [`subkey_passphrase`](Self::subkey_passphrase), change and have to be preserved by the caller beforehand.
+    ///
+    /// This function can safely be called again if it has been interrupted.
+    ///
+    /// # Errors
+    /// This function will return an error if accessing the secret store or the data directory fails. The root key is left unchanged if a mutable file can't be read.
+    pub async fn rotate(
+        &self,
+        store: &dyn SecretStore,
+        profile: &str,
+        data_path: &Path,
+        exclude: &[&Path],
+    ) -> Result<Self> {
+        let pending_entry_name = Self::pending_entry_name(profile);
+        let new_key = if let Some(key) = Self::load_entry(store, &pending_entry_name).await? {
+            key
+        } else {
+            let key = Self::new();
+            key.store_entry(store, &pending_entry_name).await?;
+            key
+        };
+
+        self.reencrypt_files(&new_key, data_path, exclude)
+            .await
+            .context("Re-encrypting mutable files")?;
+
+        new_key
+            .store_entry(store, &Self::entry_name(profile))
+            .await
+            .context("Replacing the root key")?;
+        store.delete(&pending_entry_name).await?;
+        self.clear_key_cache();
+        info!("Rotated the root key of profile {profile}");
+        Ok(new_key)
+    }
+
+    /// Re-encrypts every mutable file below `data_path` from this key to `new_key`
+    ///
+    /// Files that already decrypt under the new key are left alone, as are files that decrypt under neither key. Failing to read a file is an error.
+    pub(super) async fn reencrypt_files(
+        &self,
+        new_key: &Self,
+        data_path: &Path,
+        exclude: &[&Path],
+    ) -> Result<()> {
+        let mut dirs = vec![data_path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("Listing {}", dir.display()))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .with_context(|| format!("Listing {}", dir.display()))?
+            {
+                let path = entry.path();
+                let Ok(relative) = path.strip_prefix(data_path) else {
+                    continue;
+                };
+                if exclude.iter().any(|e| relative.starts_with(e)) {
+                    continue;
+                }
+                let file_type = entry
+                    .file_type()
+                    .await
+                    .with_context(|| format!("Inspecting {}", path.display()))?;
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                // The file name is the key id of the subdir, map it back to the subdir
+                let Some(subdir) = relative
+                    .to_str()
+                    .and_then(|key_id| {
+                        key_id
+                            .chars()
+                            .map(|c| u8::try_from(c).ok())
+                            .collect::<Option<Vec<u8>>>()
+                    })
+                    .and_then(crate::utils::path_from_bytes)
+                else {
+                    debug!("Skipping {}, it is not a mutable file", path.display());
+                    continue;
+                };
+                let old_file = self.open_mutable_file(data_path, &subdir);
+                if old_file.path != path {
+                    debug!("Skipping {}, it is not a mutable file", path.display());
+                    continue;
+                }
+                let new_file = new_key.open_mutable_file(data_path, &subdir);
+                reencrypt_file(&old_file, &new_file).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Re-encrypts a single mutable file
+///
+/// Only files that fail to decrypt are skipped. Any other error is returned, so that the root key is not replaced while a file still depends on it.
+async fn reencrypt_file(old_file: &MutableFile, new_file: &MutableFile) -> Result<()> {
+    if decrypts(new_file.read().await)? {
+        return Ok(());
+    }
+    match old_file.read().await {
+        Ok(Some(data)) => return Ok(new_file.write(data).await?),
+        Ok(None) => return Ok(()),
+        Err(MutableFileError::Decrypt { .. }) => {}
+        Err(e) => return Err(e.into()),
+    }
+    if stream_decrypts(new_file).await? {
+        return Ok(());
+    }
+    if !stream_decrypts(old_file).await? {
+        warn!(
+            "Skipping {}, it can't be decrypted with either key",
+            old_file.path.display()
+        );
+        return Ok(());
+    }
+
+    let Some(mut reader) = old_file.open_read_stream().await? else {
+        return Ok(());
+    };
+    let mut writer = new_file.open_write_stream().await?;
+    while let Some(chunk) = reader.read_chunk().await? {
+        writer.write(&chunk).await?;
+    }
+    Ok(writer.finish().await?)
+}
+
+/// Returns whether a file decrypts as a streamed mutable file
+///
+/// # Errors
+/// This function will return an error if the file can't be read.
+async fn stream_decrypts(file: &MutableFile) -> Result<bool, MutableFileError> {
+    let Some(mut reader) = file.open_read_stream().await? else {
+        return Ok(false);
+    };
+    loop {
+        match reader.read_chunk().await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(true),
+            Err(MutableFileError::Decrypt { .. }) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Maps the result of reading a file to whether it decrypted, keeping errors that are not about decryption
+fn decrypts<T>(result: Result<T, MutableFileError>) -> Result<bool, MutableFileError> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(MutableFileError::Decrypt { .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        crypto::{secret_store::MemorySecretStore, KDFSecretKey},
+        test_utils::TempDir,
+    };
+
+    #[tokio::test]
+    async fn test_files_survive_rotation() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let old_key = KDFSecretKey::new();
+        let new_key = KDFSecretKey::new();
+
+        let files = ["auth/login", "profile", "a/b/c"];
+        for name in files {
+            old_key
+                .open_mutable_file(&data_dir, name)
+                .write(name)
+                .await?;
+        }
+        let mut writer = old_key
+            .open_mutable_file(&data_dir, "media/stream")
+            .open_write_stream()
+            .await?;
+        writer.write(b"streamed").await?;
+        writer.finish().await?;
+        tokio::fs::create_dir_all(data_dir.join("matrix.db")).await?;
+        tokio::fs::write(data_dir.join("matrix.db/store.sqlite3"), b"not ours").await?;
+
+        // Running it twice simulates resuming an interrupted rotation
+        for _ in 0..2 {
+            old_key
+                .reencrypt_files(&new_key, &data_dir, &[Path::new("matrix.db")])
+                .await?;
+
+            for name in files {
+                let file = new_key.open_mutable_file(&data_dir, name);
+                assert_eq!(file.read().await?.as_deref(), Some(name.as_bytes()));
+            }
+            let reader = new_key
+                .open_mutable_file(&data_dir, "media/stream")
+                .open_read_stream()
+                .await?;
+            if let Some(mut reader) = reader {
+                assert_eq!(
+                    reader.read_chunk().await?.as_deref(),
+                    Some(&b"streamed"[..])
+                );
+            } else {
+                eyre::bail!("streamed file went missing");
+            }
+        }
+        assert_eq!(
+            tokio::fs::read(data_dir.join("matrix.db/store.sqlite3")).await?,
+            b"not ours"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unreadable_file_keeps_the_old_key() -> eyre::Result<()> {
+        let data_dir = TempDir::new()?;
+        let other_dir = TempDir::new()?;
+        let store = MemorySecretStore::default();
+        let old_key = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        old_key
+            .open_mutable_file(&data_dir, "readable")
+            .write("data")
+            .await?;
+        // Reading a directory fails with an I/O error rather than a decryption error
+        let unreadable = old_key.open_mutable_file(&data_dir, "unreadable");
+        std::os::unix::fs::symlink(&*other_dir, &unreadable.path)?;
+
+        assert!(old_key
+            .rotate(&store, "test", &data_dir, &[])
+            .await
+            .is_err());
+        let loaded = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        assert_eq!(loaded.expose_bytes(), old_key.expose_bytes());
+        assert!(KDFSecretKey::has_pending_rotation(&store, "test").await?);
+
+        // The rotation is resumed once the file can be read again
+        std::fs::remove_file(&unreadable.path)?;
+        let new_key = old_key.rotate(&store, "test", &data_dir, &[]).await?;
+        assert!(!KDFSecretKey::has_pending_rotation(&store, "test").await?);
+        let file = new_key.open_mutable_file(&data_dir, "readable");
+        assert_eq!(file.read().await?.as_deref(), Some(&b"data"[..]));
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Storage for secrets outside of the data directory
+//!
+//! Root keys are kept in a [`SecretStore`], which is the keyring of the operating system outside of tests. Keyring calls block, so the keyring store runs every one of them on the blocking thread pool, and none ever holds up an async worker thread.
+
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use eyre::{Context, Result};
+use keyring::Entry;
+use secrecy::{ExposeSecret, Secret};
+
+use super::{KeyringError, KEYRING_SERVICE};
+
+/// Storage for named secrets
+#[async_trait]
+pub trait SecretStore: Debug + Send + Sync {
+    /// Returns the secret stored under `name`, if there is one
+    ///
+    /// # Errors
+    /// This function will return an error if the store can't be accessed.
+    async fn get(&self, name: &str) -> Result<Option<Secret<String>>>;
+
+    /// Stores a secret under `name`, replacing any existing secret
+    ///
+    /// # Errors
+    /// This function will return an error if the store can't be accessed.
+    async fn set(&self, name: &str, secret: Secret<String>) -> Result<()>;
+
+    /// Deletes the secret stored under `name`, if there is one
+    ///
+    /// # Errors
+    /// This function will return an error if the store can't be accessed.
+    async fn delete(&self, name: &str) -> Result<()>;
+}
+
+/// The keyring of the operating system
+///
+/// Errors returned by the keyring can be downcast to a [`KeyringError`] to tell apart why it failed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keyring;
+
+impl Keyring {
+    /// Runs a blocking operation on a keyring entry on the blocking thread pool
+    async fn with_entry<T, F>(name: &str, action: &'static str, fun: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Entry) -> keyring::Result<T> + Send + 'static,
+    {
+        let name = name.to_owned();
+        tokio::task::spawn_blocking(move || -> Result<T> {
+            let entry = Entry::new(KEYRING_SERVICE, &name).context("Formatting keyring entry")?;
+            fun(&entry).map_err(|e| {
+                eyre::Report::new(KeyringError::classify(&e))
+                    .wrap_err(e.to_string())
+                    .wrap_err(format!("{action} {name} in keyring"))
+            })
+        })
+        .await
+        .context("Blocking keychain access")?
+    }
+}
+
+#[async_trait]
+impl SecretStore for Keyring {
+    async fn get(&self, name: &str) -> Result<Option<Secret<String>>> {
+        Self::with_entry(name, "Accessing", |entry| match entry.get_password() {
+            Ok(secret) => Ok(Some(Secret::new(secret))),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e),
+        })
+        .await
+    }
+
+    async fn set(&self, name: &str, secret: Secret<String>) -> Result<()> {
+        Self::with_entry(name, "Setting", move |entry| {
+            entry.set_password(secret.expose_secret())
+        })
+        .await
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        Self::with_entry(name, "Deleting", |entry| match entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e),
+        })
+        .await
+    }
+}
+
+/// Secret store keeping secrets in memory, so that tests don't touch the keyring
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemorySecretStore(std::sync::Mutex<std::collections::HashMap<String, Secret<String>>>);
+
+#[cfg(test)]
+impl MemorySecretStore {
+    /// Returns the stored secrets
+    fn secrets(
+        &self,
+    ) -> std::sync::MutexGuard<'_, std::collections::HashMap<String, Secret<String>>> {
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl SecretStore for MemorySecretStore {
+    async fn get(&self, name: &str) -> Result<Option<Secret<String>>> {
+        Ok(self
+            .secrets()
+            .get(name)
+            .map(|secret| Secret::new(secret.expose_secret().clone())))
+    }
+
+    async fn set(&self, name: &str, secret: Secret<String>) -> Result<()> {
+        self.secrets().insert(name.to_owned(), secret);
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.secrets().remove(name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_trait::async_trait;
+    use eyre::Result;
+    use secrecy::Secret;
+
+    use super::{MemorySecretStore, SecretStore};
+    use crate::{
+        crypto::{KDFSecretKey, KeyringError},
+        test_utils::TempDir,
+    };
+
+    /// Secret store that is locked for the first few accesses
+    #[derive(Debug, Default)]
+    struct LockedStore {
+        /// Number of accesses that still fail
+        failures: AtomicU32,
+        /// Store used once the failures are used up
+        inner: MemorySecretStore,
+    }
+
+    impl LockedStore {
+        /// Fails if the store is still locked
+        fn check(&self) -> Result<()> {
+            let locked = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if locked {
+                return Err(eyre::Report::new(KeyringError::Transient));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl SecretStore for LockedStore {
+        async fn get(&self, name: &str) -> Result<Option<Secret<String>>> {
+            self.check()?;
+            self.inner.get(name).await
+        }
+
+        async fn set(&self, name: &str, secret: Secret<String>) -> Result<()> {
+            self.check()?;
+            self.inner.set(name, secret).await
+        }
+
+        async fn delete(&self, name: &str) -> Result<()> {
+            self.check()?;
+            self.inner.delete(name).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_root_key_is_created_once() -> Result<()> {
+        let store = MemorySecretStore::default();
+        let created = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        let loaded = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        assert_eq!(created.expose_bytes(), loaded.expose_bytes());
+        let other = KDFSecretKey::load_from_store(&store, "other", 0).await?;
+        assert_ne!(created.expose_bytes(), other.expose_bytes());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transient_errors_are_retried() -> Result<()> {
+        let store = LockedStore {
+            failures: AtomicU32::new(3),
+            ..LockedStore::default()
+        };
+        assert!(KDFSecretKey::load_from_store(&store, "test", 3)
+            .await
+            .is_ok());
+
+        store.failures.store(3, Ordering::SeqCst);
+        let error = KDFSecretKey::load_from_store(&store, "test", 2)
+            .await
+            .err()
+            .ok_or_else(|| eyre::eyre!("the store was still locked"))?;
+        assert_eq!(
+            error.downcast_ref::<KeyringError>(),
+            Some(&KeyringError::Transient)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_secret_store() -> Result<()> {
+        let store = MemorySecretStore::default();
+        KDFSecretKey::check_secret_store(&store).await?;
+        assert!(store.secrets().is_empty());
+
+        let locked = LockedStore {
+            failures: AtomicU32::new(1),
+            ..LockedStore::default()
+        };
+        assert!(KDFSecretKey::check_secret_store(&locked).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotation_replaces_the_root_key() -> Result<()> {
+        let data_dir = TempDir::new()?;
+        let store = MemorySecretStore::default();
+        let old_key = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        old_key
+            .open_mutable_file(&data_dir, "auth/login")
+            .write("session")
+            .await?;
+        KDFSecretKey::schedule_rotation(&store, "test").await?;
+        assert!(KDFSecretKey::has_pending_rotation(&store, "test").await?);
+
+        let new_key = old_key.rotate(&store, "test", &data_dir, &[]).await?;
+        assert!(!KDFSecretKey::has_pending_rotation(&store, "test").await?);
+        let loaded = KDFSecretKey::load_from_store(&store, "test", 0).await?;
+        assert_eq!(loaded.expose_bytes(), new_key.expose_bytes());
+        assert_ne!(loaded.expose_bytes(), old_key.expose_bytes());
+        let file = loaded.open_mutable_file(&data_dir, "auth/login");
+        assert_eq!(file.read().await?.as_deref(), Some(&b"session"[..]));
+        Ok(())
+    }
+}
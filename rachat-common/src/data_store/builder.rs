@@ -0,0 +1,372 @@
+//! Opening data stores with explicit dependencies
+//!
+//! [`DataStore::new`] opens a profile in the project directories, with its root key in the secret store selected by its configuration. The builder allows replacing each of these, so that a data store can be opened on a temporary directory with a fixed root key, or with a client that was built beforehand.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use directories_next::ProjectDirs;
+use educe::Educe;
+use eyre::{Context, Result};
+use matrix_sdk::Client;
+use secrecy::{ExposeSecret, Secret};
+use tokio::sync::{watch, RwLock};
+
+use super::{
+    ConnectionState, DataStore, ProfileConfig, SyncState, MATRIX_STORE, MATRIX_STORE_PASSPHRASE,
+};
+use crate::crypto::{mutable_file, KDFSecretKey, Purpose, SecretStore, DEFAULT_KEYRING_RETRIES};
+
+/// Builder for a [`DataStore`]
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct DataStoreBuilder {
+    /// Name of the profile
+    profile: String,
+    /// Path to the configuration directory
+    config_dir: PathBuf,
+    /// Path to the data directory
+    data_dir: PathBuf,
+    /// Path to the cache directory
+    cache_dir: PathBuf,
+    /// Store holding the root key and pending rotations, instead of the one selected by the profile configuration
+    secret_store: Option<Arc<dyn SecretStore>>,
+    /// Passphrase of the secret file, if the profile configuration selects one
+    #[educe(Debug(ignore))]
+    secret_passphrase: Option<Secret<String>>,
+    /// Root key to use instead of the one in the secret store
+    root_key: Option<KDFSecretKey>,
+    /// Client to use instead of creating one for the configured homeserver
+    client: Option<Client>,
+}
+
+impl DataStoreBuilder {
+    /// Creates a builder for the data store of a profile in the project directories
+    #[must_use]
+    pub fn new(project_dirs: &ProjectDirs, profile: &str) -> Self {
+        let (config_dir, data_dir, cache_dir) = DataStore::profile_dirs(project_dirs, profile);
+        Self::with_dirs(profile, config_dir, data_dir, cache_dir)
+    }
+
+    /// Creates a builder for the data store of a profile in explicit directories
+    #[must_use]
+    pub fn with_dirs(
+        profile: &str,
+        config_dir: impl Into<PathBuf>,
+        data_dir: impl Into<PathBuf>,
+        cache_dir: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            profile: profile.to_owned(),
+            config_dir: config_dir.into(),
+            data_dir: data_dir.into(),
+            cache_dir: cache_dir.into(),
+            secret_store: None,
+            secret_passphrase: None,
+            root_key: None,
+            client: None,
+        }
+    }
+
+    /// Sets the store the root key and pending rotations are kept in
+    ///
+    /// By default, the store selected by [`ProfileConfig::secret_backend`] is used.
+    #[must_use]
+    pub fn secret_store(mut self, secret_store: Arc<dyn SecretStore>) -> Self {
+        self.secret_store = Some(secret_store);
+        self
+    }
+
+    /// Sets the passphrase of the secret file, for profiles that keep their root key in one
+    #[must_use]
+    pub fn secret_passphrase(mut self, passphrase: Secret<String>) -> Self {
+        self.secret_passphrase = Some(passphrase);
+        self
+    }
+
+    /// Uses a fixed root key, instead of loading it from the secret store
+    ///
+    /// Pending rotations are still performed, and the rotated root key is then stored in the secret store.
+    #[must_use]
+    pub fn root_key(mut self, root_key: KDFSecretKey) -> Self {
+        self.root_key = Some(root_key);
+        self
+    }
+
+    /// Uses a client that has already been built, instead of creating one for the configured homeserver
+    #[must_use]
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Returns the name of the profile, and its configuration and data directories
+    pub(super) fn profile_dirs(&self) -> (&str, &Path, &Path) {
+        (&self.profile, &self.config_dir, &self.data_dir)
+    }
+
+    /// Returns the secret store, opening the one selected by `config` if none has been set
+    ///
+    /// The opened store is kept, so that [`build`](Self::build) uses the same one.
+    ///
+    /// # Errors
+    /// This function returns a [`PassphraseRequiredError`](crate::crypto::PassphraseRequiredError) if `config` selects a secret file, but no passphrase was given.
+    pub(super) fn resolve_secret_store(
+        &mut self,
+        config: Option<&ProfileConfig>,
+    ) -> Result<Arc<dyn SecretStore>> {
+        if let Some(secret_store) = &self.secret_store {
+            return Ok(Arc::clone(secret_store));
+        }
+        let secret_store = config
+            .map(|config| config.secret_backend)
+            .unwrap_or_default()
+            .open(&self.config_dir, self.secret_passphrase.as_ref())?;
+        self.secret_store = Some(Arc::clone(&secret_store));
+        Ok(secret_store)
+    }
+
+    /// Opens the data store
+    ///
+    /// # Errors
+    /// This function returns an error if the directories can't be created, the profile configuration can't be read or parsed, the root key can't be loaded or rotated, or if the client for the configured homeserver can't be created. If the profile keeps its root key in a secret file and no passphrase was given, the error is a [`PassphraseRequiredError`](crate::crypto::PassphraseRequiredError).
+    pub async fn build(mut self) -> Result<Arc<DataStore>> {
+        tokio::fs::create_dir_all(&self.data_dir)
+            .await
+            .context("Creating data directory")?;
+        tokio::fs::create_dir_all(&self.cache_dir)
+            .await
+            .context("Creating cache directory")?;
+        tokio::fs::create_dir_all(&self.config_dir)
+            .await
+            .context("Creating config directory")?;
+        // Nothing in the profile is being written yet, so every temporary file is left over from a crash
+        for dir in [&self.data_dir, &self.config_dir] {
+            mutable_file::remove_stale_temp_files(dir)
+                .await
+                .context("Removing stale temporary files")?;
+        }
+
+        let config = ProfileConfig::load(&self.config_dir)
+            .await
+            .context("Loading the profile config")?;
+
+        let secret_store = self.resolve_secret_store(config.as_ref())?;
+        let Self {
+            profile,
+            config_dir,
+            data_dir,
+            cache_dir,
+            root_key,
+            client,
+            ..
+        } = self;
+
+        let mut root_key = match root_key {
+            Some(root_key) => root_key,
+            None => KDFSecretKey::load_from_store(
+                secret_store.as_ref(),
+                &profile,
+                DEFAULT_KEYRING_RETRIES,
+            )
+            .await
+            .context("Obtaining KDF secret key")?,
+        };
+
+        if KDFSecretKey::has_pending_rotation(secret_store.as_ref(), &profile)
+            .await
+            .context("Checking for a pending root key rotation")?
+        {
+            // The matrix store can't be re-keyed, so its passphrase is carried over
+            let passphrase_file = root_key.open_mutable_file(&data_dir, MATRIX_STORE_PASSPHRASE);
+            if !passphrase_file.exists().await? {
+                passphrase_file
+                    .write(
+                        root_key
+                            .subkey_passphrase(Purpose::MatrixSdk)
+                            .expose_secret(),
+                    )
+                    .await
+                    .context("Preserving the matrix store passphrase")?;
+            }
+            let store_path = config.as_ref().map_or_else(
+                || data_dir.join(MATRIX_STORE),
+                |config| config.matrix_store_path(&data_dir),
+            );
+            let exclude = store_path
+                .strip_prefix(&data_dir)
+                .map_or_else(|_| Vec::new(), |path| vec![path]);
+            root_key = root_key
+                .rotate(secret_store.as_ref(), &profile, &data_dir, &exclude)
+                .await
+                .context("Rotating the root key")?;
+        }
+
+        let has_client = client.is_some();
+        let res = Arc::new(DataStore {
+            profile,
+            root_key,
+            secret_store,
+            config_dir,
+            config: RwLock::new(config.clone()),
+            data_dir,
+            cache_dir,
+            client: RwLock::new(client.map(Arc::new)),
+            sync_task: RwLock::new(None),
+            session_task: RwLock::new(None),
+            rooms_changed: Arc::new(watch::Sender::new(())),
+            connection_state: Arc::new(watch::Sender::new(ConnectionState::Offline)),
+            sync_state: Arc::new(watch::Sender::new(SyncState::InitialSyncInProgress)),
+            offline: AtomicBool::new(false),
+            own_profile_changed: watch::Sender::new(()),
+            activity: watch::Sender::new(()),
+            idle_timeout: Arc::new(watch::Sender::new(())),
+            idle_task: RwLock::new(None),
+        });
+
+        if let Some(server_name) = config
+            .and_then(|config| config.server_name)
+            .filter(|_| !has_client)
+        {
+            Arc::clone(&res)
+                .set_homeserver(server_name)
+                .await
+                .context("Preparing the client")?;
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use secrecy::Secret;
+
+    use crate::{
+        crypto::{secret_store::MemorySecretStore, KDFSecretKey, PassphraseRequiredError},
+        test_utils::{builder, local_client, TempDir},
+    };
+
+    #[tokio::test]
+    async fn test_fixed_root_key() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let root_key = KDFSecretKey::new();
+        let data_store = builder(&dir)
+            .secret_store(Arc::new(MemorySecretStore::default()))
+            .root_key(root_key.clone())
+            .build()
+            .await?;
+        assert!(!data_store.has_client().await);
+
+        data_store.open_mutable_file("test").write("data").await?;
+        let file = root_key.open_mutable_file(dir.join("data"), "test");
+        assert_eq!(file.read().await?.as_deref(), Some(&b"data"[..]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pending_rotation_uses_the_secret_store() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Arc::new(MemorySecretStore::default());
+        let data_store = builder(&dir)
+            .secret_store(Arc::clone(&store) as _)
+            .build()
+            .await?;
+        data_store.open_mutable_file("test").write("data").await?;
+        data_store.schedule_root_key_rotation().await?;
+        drop(data_store);
+
+        let data_store = builder(&dir)
+            .secret_store(Arc::clone(&store) as _)
+            .build()
+            .await?;
+        assert!(!KDFSecretKey::has_pending_rotation(store.as_ref(), "test").await?);
+        let file = data_store.open_mutable_file("test");
+        assert_eq!(file.read().await?.as_deref(), Some(&b"data"[..]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secret_file_is_selected_by_the_config() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.join("config"))?;
+        std::fs::write(
+            dir.join("config/config.json"),
+            r#"{"server_name":"example.com","secret_backend":"file"}"#,
+        )?;
+        let client = local_client().await?;
+        let open = || builder(&dir).client(client.clone());
+
+        let error = open()
+            .build()
+            .await
+            .err()
+            .ok_or_else(|| eyre::eyre!("opened without a passphrase"))?;
+        assert!(error.downcast_ref::<PassphraseRequiredError>().is_some());
+
+        let passphrase = || Secret::new("correct horse battery staple".to_owned());
+        let data_store = open().secret_passphrase(passphrase()).build().await?;
+        data_store.open_mutable_file("test").write("data").await?;
+        drop(data_store);
+        assert!(dir.join("config/secrets.cbor").is_file());
+
+        let data_store = open().secret_passphrase(passphrase()).build().await?;
+        let file = data_store.open_mutable_file("test");
+        assert_eq!(file.read().await?.as_deref(), Some(&b"data"[..]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_config_is_not_ignored() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.join("config"))?;
+        std::fs::write(
+            dir.join("config/config.json"),
+            "{\n  \"server_name\": \"example.com\",\n  \"secret_backend\": \"fil\"\n}",
+        )?;
+        let store = Arc::new(MemorySecretStore::default());
+
+        let error = builder(&dir)
+            .secret_store(Arc::clone(&store) as _)
+            .build()
+            .await
+            .err()
+            .ok_or_else(|| eyre::eyre!("opened with an invalid config"))?;
+        let message = format!("{error:#}");
+        assert!(message.contains("config.json (line 3"), "{message}");
+        assert!(!KDFSecretKey::exists_in_store(store.as_ref(), "test").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_config_is_persisted_with_injected_client() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.join("config"))?;
+        std::fs::write(
+            dir.join("config/config.json"),
+            r#"{"server_name":"example.com"}"#,
+        )?;
+        let client = local_client().await?;
+        let data_store = builder(&dir)
+            .secret_store(Arc::new(MemorySecretStore::default()))
+            .client(client)
+            .build()
+            .await?;
+        assert!(data_store.has_client().await);
+
+        data_store
+            .set_idle_logout(Some(Duration::from_secs(30)))
+            .await?;
+        assert_eq!(
+            data_store.idle_logout().await,
+            Some(Duration::from_secs(30))
+        );
+        let config = std::fs::read_to_string(dir.join("config/config.json"))?;
+        assert!(config.contains(r#""idle_logout_secs":30"#), "{config}");
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+//! Devices of the logged-in user and their verification
+
+use std::sync::Arc;
+
+use eyre::{Context, Result};
+use futures::StreamExt;
+use matrix_sdk::{
+    encryption::verification::{
+        Emoji, SasState, SasVerification, VerificationRequest, VerificationRequestState,
+    },
+    ruma::{DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId},
+};
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, warn};
+
+use super::DataStore;
+
+/// Device of the logged-in user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// ID of the device
+    pub device_id: OwnedDeviceId,
+    /// Display name of the device, if one is set
+    pub display_name: Option<String>,
+    /// Whether the device has been verified
+    pub verified: bool,
+    /// When the device was last seen by the homeserver
+    pub last_seen: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// Progress of a device verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationEvent {
+    /// The other device accepted the request, and the short authentication string is being negotiated
+    Accepted,
+    /// The emojis to compare with the other device, as pairs of symbol and description
+    Emojis(Vec<(&'static str, &'static str)>),
+    /// The numbers to compare with the other device, if it does not support emojis
+    Decimals(u16, u16, u16),
+    /// The device has been verified
+    Done,
+    /// The verification has been cancelled, with the reason
+    Cancelled(String),
+}
+
+/// An ongoing verification of another device
+#[derive(Debug)]
+pub struct DeviceVerification {
+    /// The verification request sent to the other device
+    request: VerificationRequest,
+    /// The short authentication string verification, once it has started
+    sas: Arc<RwLock<Option<SasVerification>>>,
+    /// Progress of the verification
+    events: mpsc::UnboundedReceiver<VerificationEvent>,
+}
+
+impl DeviceVerification {
+    /// Waits for the next step of the verification
+    ///
+    /// Returns `None` once the verification is done or has been cancelled.
+    pub async fn next_event(&mut self) -> Option<VerificationEvent> {
+        self.events.recv().await
+    }
+
+    /// Confirms that the short authentication strings match
+    ///
+    /// # Errors
+    /// This function returns an error if the strings have not been shown yet, or if the confirmation could not be sent.
+    pub async fn confirm(&self) -> Result<()> {
+        self.sas()
+            .await?
+            .confirm()
+            .await
+            .context("Confirming the verification")
+    }
+
+    /// Reports that the short authentication strings don't match, cancelling the verification
+    ///
+    /// # Errors
+    /// This function returns an error if the strings have not been shown yet, or if the cancellation could not be sent.
+    pub async fn mismatch(&self) -> Result<()> {
+        self.sas()
+            .await?
+            .mismatch()
+            .await
+            .context("Cancelling the verification")
+    }
+
+    /// Cancels the verification
+    ///
+    /// # Errors
+    /// This function returns an error if the cancellation could not be sent.
+    pub async fn cancel(&self) -> Result<()> {
+        self.request
+            .cancel()
+            .await
+            .context("Cancelling the verification")
+    }
+
+    /// Returns the short authentication string verification
+    async fn sas(&self) -> Result<SasVerification> {
+        self.sas
+            .read()
+            .await
+            .clone()
+            .ok_or_else(|| eyre::eyre!("The verification has not started yet"))
+    }
+}
+
+impl DataStore {
+    /// Returns the devices of the logged-in user
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in, or if the devices could not be fetched.
+    pub async fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        self.ensure_online()?;
+        let client = self.logged_in_client().await?;
+        let user_id = client
+            .user_id()
+            .ok_or_else(|| eyre::eyre!("Not logged in"))?;
+        let user_devices = client
+            .encryption()
+            .get_user_devices(user_id)
+            .await
+            .context("Loading the device keys")?;
+        let response = client.devices().await.context("Fetching the devices")?;
+        Ok(response
+            .devices
+            .into_iter()
+            .map(|device| DeviceInfo {
+                verified: user_devices
+                    .get(&device.device_id)
+                    .is_some_and(|d| d.is_verified()),
+                device_id: device.device_id,
+                display_name: device.display_name,
+                last_seen: device.last_seen_ts,
+            })
+            .collect())
+    }
+
+    /// Requests the verification of another device of the logged-in user
+    ///
+    /// The emojis or numbers to compare are reported by the returned [`DeviceVerification`].
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in, the device is unknown, or the request could not be sent.
+    pub async fn request_verification(&self, device_id: &DeviceId) -> Result<DeviceVerification> {
+        self.ensure_online()?;
+        let client = self.logged_in_client().await?;
+        let user_id = client
+            .user_id()
+            .ok_or_else(|| eyre::eyre!("Not logged in"))?;
+        let device = client
+            .encryption()
+            .get_device(user_id, device_id)
+            .await
+            .context("Loading the device keys")?
+            .ok_or_else(|| eyre::eyre!("Unknown device {device_id}"))?;
+        let request = device
+            .request_verification()
+            .await
+            .with_context(|| format!("Requesting the verification of {device_id}"))?;
+
+        let sas = Arc::new(RwLock::new(None));
+        let (sender, events) = mpsc::unbounded_channel();
+        tokio::spawn(drive_verification(
+            request.clone(),
+            Arc::clone(&sas),
+            sender,
+        ));
+        Ok(DeviceVerification {
+            request,
+            sas,
+            events,
+        })
+    }
+}
+
+/// Follows a verification request, reporting its progress until it is done or cancelled
+async fn drive_verification(
+    request: VerificationRequest,
+    sas_slot: Arc<RwLock<Option<SasVerification>>>,
+    events: mpsc::UnboundedSender<VerificationEvent>,
+) {
+    let Some(sas) = wait_for_sas(&request, &events).await else {
+        return;
+    };
+
+    let mut changes = sas.changes();
+    if !sas.we_started() {
+        if let Err(e) = sas.accept().await {
+            warn!("Failed to accept the verification: {e:#?}");
+            events
+                .send(VerificationEvent::Cancelled(e.to_string()))
+                .ok();
+            return;
+        }
+    }
+    *sas_slot.write().await = Some(sas.clone());
+    events.send(VerificationEvent::Accepted).ok();
+
+    // The keys may have been exchanged before the changes were subscribed to
+    let mut last_event = current_sas_event(&sas);
+    if let Some(event) = last_event.clone() {
+        events.send(event).ok();
+    }
+    while let Some(state) = changes.next().await {
+        let event = match state {
+            SasState::KeysExchanged {
+                emojis: Some(emojis),
+                ..
+            } => emoji_event(&emojis.emojis),
+            SasState::KeysExchanged { decimals, .. } => {
+                VerificationEvent::Decimals(decimals.0, decimals.1, decimals.2)
+            }
+            SasState::Done { .. } => {
+                events.send(VerificationEvent::Done).ok();
+                return;
+            }
+            SasState::Cancelled(info) => {
+                events
+                    .send(VerificationEvent::Cancelled(info.reason().to_owned()))
+                    .ok();
+                return;
+            }
+            _ => continue,
+        };
+        if last_event.as_ref() != Some(&event) {
+            last_event = Some(event.clone());
+            events.send(event).ok();
+        }
+    }
+}
+
+/// Waits for a verification request to turn into a short authentication string verification
+///
+/// Returns `None` if the request was cancelled or finished otherwise, which has already been reported to `events`.
+async fn wait_for_sas(
+    request: &VerificationRequest,
+    events: &mpsc::UnboundedSender<VerificationEvent>,
+) -> Option<SasVerification> {
+    let mut changes = request.changes();
+    loop {
+        match changes.next().await {
+            Some(VerificationRequestState::Ready { .. }) => match request.start_sas().await {
+                Ok(Some(sas)) => return Some(sas),
+                Ok(None) if !matches!(request.state(), VerificationRequestState::Ready { .. }) => {
+                    // The other device started the verification at the same time, its state change follows
+                    debug!("The verification request moved on before it could be started");
+                }
+                Ok(None) => {
+                    warn!("The devices have no short authentication string verification method in common");
+                    events
+                        .send(VerificationEvent::Cancelled(
+                            "No common verification method".to_owned(),
+                        ))
+                        .ok();
+                    if let Err(e) = request.cancel().await {
+                        warn!("Failed to cancel the verification: {e:#?}");
+                    }
+                    return None;
+                }
+                Err(e) => {
+                    warn!("Failed to start the verification: {e:#?}");
+                    events
+                        .send(VerificationEvent::Cancelled(e.to_string()))
+                        .ok();
+                    return None;
+                }
+            },
+            Some(VerificationRequestState::Transitioned { verification }) => {
+                if let Some(sas) = verification.sas() {
+                    return Some(sas);
+                }
+                warn!("The other device started an unsupported verification method");
+                events
+                    .send(VerificationEvent::Cancelled(
+                        "Unsupported verification method".to_owned(),
+                    ))
+                    .ok();
+                if let Err(e) = request.cancel().await {
+                    warn!("Failed to cancel the verification: {e:#?}");
+                }
+                return None;
+            }
+            Some(VerificationRequestState::Cancelled(info)) => {
+                events
+                    .send(VerificationEvent::Cancelled(info.reason().to_owned()))
+                    .ok();
+                return None;
+            }
+            Some(VerificationRequestState::Done) | None => return None,
+            Some(_) => {}
+        }
+    }
+}
+
+/// Returns the short authentication string of a verification, if the keys have already been exchanged
+fn current_sas_event(sas: &SasVerification) -> Option<VerificationEvent> {
+    sas.emoji().map_or_else(
+        || {
+            sas.decimals()
+                .map(|(a, b, c)| VerificationEvent::Decimals(a, b, c))
+        },
+        |emojis| Some(emoji_event(&emojis)),
+    )
+}
+
+/// Returns the event reporting the emojis to compare
+fn emoji_event(emojis: &[Emoji]) -> VerificationEvent {
+    VerificationEvent::Emojis(
+        emojis
+            .iter()
+            .map(|emoji| (emoji.symbol, emoji.description))
+            .collect(),
+    )
+}
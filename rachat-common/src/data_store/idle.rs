@@ -0,0 +1,151 @@
+//! Logging out after a period of inactivity
+//!
+//! On shared machines, the session can be ended automatically once the user has been inactive for [`ProfileConfig::idle_logout_secs`](super::ProfileConfig::idle_logout_secs). The frontend reports user input with [`DataStore::mark_active`] and reacts to [`DataStore::watch_idle_timeouts`] by logging out.
+
+use std::{sync::Arc, time::Duration};
+
+use eyre::{Context, Result};
+use futures::{stream, Stream};
+use tokio::sync::watch;
+
+use super::DataStore;
+
+impl DataStore {
+    /// Records user activity, restarting the idle timer
+    pub fn mark_active(&self) {
+        self.activity.send_replace(());
+    }
+
+    /// Returns how long the user may be inactive before being logged out
+    ///
+    /// Returns `None` if idle logout is disabled.
+    pub async fn idle_logout(&self) -> Option<Duration> {
+        self.config
+            .read()
+            .await
+            .as_ref()
+            .and_then(|config| config.idle_logout_secs)
+            .filter(|secs| *secs != 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Sets how long the user may be inactive before being logged out, `None` disables idle logout
+    ///
+    /// The timeout is rounded up to whole seconds. The idle timer is restarted with the new timeout.
+    ///
+    /// # Errors
+    /// This function returns an error if no homeserver has been selected, or the profile configuration could not be updated.
+    pub async fn set_idle_logout(&self, timeout: Option<Duration>) -> Result<()> {
+        let mut config = self.config.write().await;
+        let profile_config = config
+            .as_mut()
+            .ok_or_else(|| eyre::eyre!("No homeserver has been selected"))?;
+        profile_config.idle_logout_secs = timeout.map(timeout_secs);
+        // The lock is held while writing, so that concurrent writes land in order
+        profile_config
+            .save(&self.config_dir)
+            .await
+            .context("Updating the config")?;
+        drop(config);
+        self.schedule_idle_logout().await;
+        Ok(())
+    }
+
+    /// Returns a stream that yields whenever the user has been inactive for longer than the idle timeout
+    ///
+    /// The data store does not log out by itself, the frontend is expected to call [`logout`](Self::logout) and show the login page.
+    pub fn watch_idle_timeouts(&self) -> impl Stream<Item = ()> {
+        let receiver = self.idle_timeout.subscribe();
+        stream::unfold(receiver, |mut receiver| async move {
+            receiver.changed().await.ok()?;
+            Some(((), receiver))
+        })
+    }
+
+    /// Restarts the idle timer, if the user is logged in and idle logout is enabled
+    pub(super) async fn schedule_idle_logout(&self) {
+        self.cancel_idle_logout().await;
+        let Some(timeout) = self.idle_logout().await else {
+            return;
+        };
+        if !self.is_logged_in().await {
+            return;
+        }
+        *self.idle_task.write().await = Some(tokio::spawn(idle_timer(
+            timeout,
+            self.activity.subscribe(),
+            Arc::clone(&self.idle_timeout),
+        )));
+    }
+
+    /// Stops the idle timer, if it is running
+    pub(super) async fn cancel_idle_logout(&self) {
+        let task = self.idle_task.write().await.take();
+        if let Some(task) = task {
+            task.abort();
+        }
+    }
+}
+
+/// Returns the number of seconds of a timeout, rounded up so that sub-second timeouts don't disable idle logout
+fn timeout_secs(timeout: Duration) -> u64 {
+    timeout
+        .as_secs()
+        .saturating_add(u64::from(timeout.subsec_nanos() != 0))
+}
+
+/// Waits until there has been no activity for `timeout`, then notifies `idle_timeout`
+async fn idle_timer(
+    timeout: Duration,
+    mut activity: watch::Receiver<()>,
+    idle_timeout: Arc<watch::Sender<()>>,
+) {
+    loop {
+        match tokio::time::timeout(timeout, activity.changed()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return,
+            Err(_) => {
+                idle_timeout.send_replace(());
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::watch;
+
+    use super::{idle_timer, timeout_secs};
+
+    #[test]
+    fn test_timeouts_are_rounded_up() {
+        assert_eq!(timeout_secs(Duration::from_millis(1)), 1);
+        assert_eq!(timeout_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(timeout_secs(Duration::from_secs(30)), 30);
+        assert_eq!(timeout_secs(Duration::MAX), u64::MAX);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_activity_restarts_the_idle_timer() {
+        let activity = watch::Sender::new(());
+        let idle_timeout = Arc::new(watch::Sender::new(()));
+        let timeouts = idle_timeout.subscribe();
+        let timer = tokio::spawn(idle_timer(
+            Duration::from_secs(45),
+            activity.subscribe(),
+            Arc::clone(&idle_timeout),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        activity.send_replace(());
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(!timeouts.has_changed().unwrap_or(true));
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(timeouts.has_changed().unwrap_or(false));
+        assert!(timer.is_finished());
+    }
+}
@@ -0,0 +1,239 @@
+//! Maintenance of the matrix store
+//!
+//! The sqlite databases of the matrix store only ever grow: deleted rows leave free pages behind, and cached media is never evicted. Compacting the store prunes the media cache down to its configured size and then vacuums the databases.
+
+use std::{path::Path, time::Duration};
+
+use eyre::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use tokio::fs;
+use tracing::{debug, info};
+
+use super::{DataStore, MATRIX_STORE};
+
+/// Database of the matrix store holding the room state and the media cache
+const STATE_DATABASE: &str = "matrix-sdk-state.sqlite3";
+
+/// Database of the matrix store holding the encryption state
+pub(super) const CRYPTO_DATABASE: &str = "matrix-sdk-crypto.sqlite3";
+
+/// Suffixes of the files sqlite keeps next to a database in write-ahead log mode
+const DATABASE_FILE_SUFFIXES: [&str; 3] = ["", "-wal", "-shm"];
+
+/// How long to wait for the matrix client to release a database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl DataStore {
+    /// Compacts the matrix store, returning the number of bytes reclaimed
+    ///
+    /// The media cache is pruned down to [`ProfileConfig::media_cache_max_bytes`](super::ProfileConfig::media_cache_max_bytes), dropping the media that was cached first, and the databases are vacuumed afterwards. The store does not record when media was last used, so the cache can only be bounded by size. The background sync is paused while the store is compacted, and calls to [`start_sync`](Self::start_sync) and [`stop_sync`](Self::stop_sync) wait until it has been resumed.
+    ///
+    /// # Errors
+    /// This function returns an error if the store could not be read or compacted.
+    pub async fn compact_store(&self) -> Result<u64> {
+        let (store_path, max_media_bytes) = {
+            let config = self.config.read().await;
+            config.as_ref().map_or_else(
+                || (self.data_dir.join(MATRIX_STORE), None),
+                |config| {
+                    (
+                        config.matrix_store_path(&self.data_dir),
+                        config.media_cache_max_bytes,
+                    )
+                },
+            )
+        };
+
+        // The sync task stays locked until the sync is resumed, so that nothing else can start or stop it in the meantime
+        let mut sync_task = self.sync_task.write().await;
+        let was_syncing = sync_task.as_ref().is_some_and(|task| !task.is_finished());
+        if was_syncing {
+            self.stop_sync_locked(&mut sync_task).await;
+        }
+        let result = async {
+            let size_before = store_size(&store_path).await?;
+            let path = store_path.clone();
+            tokio::task::spawn_blocking(move || compact_databases(&path, max_media_bytes))
+                .await
+                .context("Compacting the matrix store")??;
+            let size_after = store_size(&store_path).await?;
+            Ok::<_, eyre::Report>(size_before.saturating_sub(size_after))
+        }
+        .await;
+        if was_syncing {
+            self.start_sync_locked(&mut sync_task).await;
+        }
+        drop(sync_task);
+
+        let reclaimed = result?;
+        info!("Compacted the matrix store, reclaimed {reclaimed} bytes");
+        Ok(reclaimed)
+    }
+}
+
+/// Deletes the databases of the matrix store
+///
+/// The store path is configurable and may be shared with other files, so only the files of the databases are removed. The directory is removed as well if nothing else is left in it.
+///
+/// # Errors
+/// This function returns an error if a database file could not be removed.
+pub(super) async fn remove_store(store_path: &Path) -> Result<()> {
+    for name in [STATE_DATABASE, CRYPTO_DATABASE] {
+        for suffix in DATABASE_FILE_SUFFIXES {
+            let path = store_path.join(format!("{name}{suffix}"));
+            match fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Deleting {}", path.display()));
+                }
+            }
+        }
+    }
+    if let Err(e) = fs::remove_dir(store_path).await {
+        debug!("Keeping {}: {e}", store_path.display());
+    }
+    Ok(())
+}
+
+/// Returns the total size of the files in the matrix store
+async fn store_size(store_path: &Path) -> Result<u64> {
+    let mut entries = match fs::read_dir(store_path).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Listing {}", store_path.display()));
+        }
+    };
+    let mut size = 0;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("Listing {}", store_path.display()))?
+    {
+        let metadata = entry
+            .metadata()
+            .await
+            .with_context(|| format!("Reading the size of {}", entry.path().display()))?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Prunes the media cache and vacuums the databases of the matrix store
+///
+/// Databases that don't exist yet are skipped.
+fn compact_databases(store_path: &Path, max_media_bytes: Option<u64>) -> Result<()> {
+    for name in [STATE_DATABASE, CRYPTO_DATABASE] {
+        let path = store_path.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        let connection = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .context("Setting the busy timeout")?;
+        if name == STATE_DATABASE {
+            if let Some(max_media_bytes) = max_media_bytes {
+                let pruned = prune_media(&connection, max_media_bytes)
+                    .with_context(|| format!("Pruning the media cache in {}", path.display()))?;
+                info!("Pruned {pruned} files from the media cache");
+            }
+        }
+        connection
+            .execute_batch("VACUUM")
+            .with_context(|| format!("Vacuuming {}", path.display()))?;
+        // Vacuuming goes through the write-ahead log, so it has to be emptied to actually free the space
+        connection
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .with_context(|| format!("Checkpointing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Deletes the oldest cached media until the cache is at most `max_bytes` large, returning the number of deleted files
+///
+/// Replacing a cache entry gives it a new row id, so the row ids order the cache from the oldest to the newest entry.
+fn prune_media(connection: &Connection, max_bytes: u64) -> rusqlite::Result<usize> {
+    connection.execute(
+        "DELETE FROM media WHERE rowid IN (
+            SELECT rowid FROM (
+                SELECT rowid, SUM(LENGTH(data)) OVER (ORDER BY rowid DESC) AS kept FROM media
+            ) WHERE kept > ?
+        )",
+        [i64::try_from(max_bytes).unwrap_or(i64::MAX)],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{compact_databases, remove_store, CRYPTO_DATABASE, STATE_DATABASE};
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn test_media_cache_is_pruned_oldest_first() -> eyre::Result<()> {
+        let store_path = TempDir::new()?;
+        let connection = Connection::open(store_path.join(STATE_DATABASE))?;
+        connection.execute_batch(
+            r#"CREATE TABLE "media" (
+                "uri" BLOB NOT NULL,
+                "format" BLOB NOT NULL,
+                "data" BLOB NOT NULL,
+                PRIMARY KEY ("uri", "format")
+            );"#,
+        )?;
+        for uri in ["a", "b", "c", "d"] {
+            connection.execute(
+                "INSERT OR REPLACE INTO media (uri, format, data) VALUES (?, 'file', zeroblob(100))",
+                [uri],
+            )?;
+        }
+        // Replacing an entry makes it the newest one
+        connection.execute(
+            "INSERT OR REPLACE INTO media (uri, format, data) VALUES ('a', 'file', zeroblob(100))",
+            [],
+        )?;
+
+        compact_databases(&store_path, Some(250))?;
+        let mut statement = connection.prepare("SELECT uri FROM media ORDER BY uri")?;
+        let kept = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(kept, ["a", "d"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_store_only_removes_the_databases() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let store_path = dir.join("matrix.db");
+        std::fs::create_dir_all(&store_path)?;
+        for name in [
+            STATE_DATABASE.to_owned(),
+            format!("{STATE_DATABASE}-wal"),
+            format!("{CRYPTO_DATABASE}-shm"),
+            CRYPTO_DATABASE.to_owned(),
+            "notes.txt".to_owned(),
+        ] {
+            std::fs::write(store_path.join(name), b"")?;
+        }
+
+        remove_store(&store_path).await?;
+        let left = std::fs::read_dir(&store_path)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(left, ["notes.txt"]);
+
+        // A dedicated store directory is removed entirely
+        std::fs::remove_file(store_path.join("notes.txt"))?;
+        std::fs::write(store_path.join(STATE_DATABASE), b"")?;
+        remove_store(&store_path).await?;
+        assert!(!store_path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! Media downloads
+
+use matrix_sdk::{
+    media::{MediaFormat, MediaRequest, MediaThumbnailSize},
+    ruma::{events::room::MediaSource, MxcUri},
+};
+use tracing::warn;
+
+use super::DataStore;
+
+impl DataStore {
+    /// Fetches media like avatars, returning the contents of the file
+    ///
+    /// If `size` is given, a thumbnail of roughly that size is fetched instead of the original file. Media is cached in the matrix store, and only fetched from the homeserver if it is not cached yet and offline mode is disabled.
+    ///
+    /// Returns `None` if there is no client or the media is invalid or unavailable, so that a broken image can be rendered as a placeholder.
+    pub async fn fetch_media(
+        &self,
+        mxc: &MxcUri,
+        size: Option<MediaThumbnailSize>,
+    ) -> Option<Vec<u8>> {
+        if !mxc.is_valid() {
+            warn!("Not fetching invalid media URL {mxc}");
+            return None;
+        }
+        let client = self.client.read().await.clone()?;
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc.to_owned()),
+            format: size.map_or(MediaFormat::File, MediaFormat::Thumbnail),
+        };
+        if self.is_offline() {
+            return match client.store().get_media_content(&request).await {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!("Failed to load {mxc} from the cache: {e:#?}");
+                    None
+                }
+            };
+        }
+        match client.media().get_media_content(&request, true).await {
+            Ok(content) => Some(content),
+            Err(e) => {
+                warn!("Failed to fetch {mxc}: {e:#?}");
+                None
+            }
+        }
+    }
+}
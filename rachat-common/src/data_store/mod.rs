@@ -0,0 +1,1200 @@
+//! Backing datastore for the client
+//!
+//! Frontend code renders values from this module
+use directories_next::ProjectDirs;
+use educe::Educe;
+use eyre::{Context, Result};
+use futures::{Stream, StreamExt};
+use matrix_sdk::{
+    config::SyncSettings,
+    matrix_auth::MatrixSession,
+    reqwest::Url,
+    ruma::api::client::{discovery::get_supported_versions, error::ErrorKind},
+    AuthSession, Client, LoopCtrl, OwnedServerName, ServerName,
+};
+use matrix_sdk_base::store::StateStoreDataKey;
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{watch, RwLock},
+    task::JoinHandle,
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::crypto::{
+    mutable_file::{self, MutableFile},
+    KDFSecretKey, Purpose, SecretBackend, SecretStore,
+};
+
+pub mod builder;
+pub mod devices;
+pub mod idle;
+pub mod maintenance;
+pub mod media;
+pub mod profile;
+pub mod recovery;
+pub mod registration;
+pub mod registry;
+pub mod rooms;
+pub mod transfer;
+
+/// Timeout of a single sync request
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Initial delay before retrying a failed sync
+const SYNC_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum delay before retrying a failed sync
+const SYNC_MAX_BACKOFF: Duration = Duration::from_secs(64);
+
+/// Default location of the matrix store, relative to the data directory
+const MATRIX_STORE: &str = "matrix.db";
+
+/// Mutable file holding the matrix store passphrase after a root key rotation
+const MATRIX_STORE_PASSPHRASE: &str = "keys/matrix-rust-sdk";
+
+/// Name of the profile configuration file in the configuration directory
+const PROFILE_CONFIG: &str = "config.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Configuration for a single profile
+pub struct ProfileConfig {
+    /// The server name to connect to
+    ///
+    /// This is `None` after the homeserver has been reset. The rest of the configuration is kept in that case, as it still applies to the profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<OwnedServerName>,
+    /// Location of the matrix sqlite store
+    ///
+    /// Relative paths are relative to the data directory of the profile. Defaults to `matrix.db`.
+    ///
+    /// Encryption at rest of the store is the responsibility of the database. It is opened with a passphrase derived from the root key, and it encrypts its contents with that passphrase itself. Unlike mutable files, the store is not encrypted by the data store, and it is skipped by root key rotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_path: Option<PathBuf>,
+    /// Maximum size of the media cache in bytes, enforced by [`DataStore::compact_store`]
+    ///
+    /// The media cache is unbounded if this is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_cache_max_bytes: Option<u64>,
+    /// Number of seconds of inactivity after which the user is logged out
+    ///
+    /// Idle logout is disabled if this is not set or zero.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_logout_secs: Option<u64>,
+    /// Where the root key of the profile is stored
+    ///
+    /// This is read before the root key is loaded, so changing it only takes effect the next time the profile is opened. Existing secrets are not moved over.
+    #[serde(default, skip_serializing_if = "SecretBackend::is_default")]
+    pub secret_backend: SecretBackend,
+}
+
+impl ProfileConfig {
+    /// Loads the profile configuration from the configuration directory, returning `None` if there is none
+    ///
+    /// The configuration decides where the root key is stored, so a file that can't be read or parsed is an error, rather than falling back to the defaults.
+    pub(crate) async fn load(config_dir: &Path) -> Result<Option<Self>> {
+        let path = config_dir.join(PROFILE_CONFIG);
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        serde_json::from_str(&contents).map(Some).map_err(|e| {
+            let (line, column) = (e.line(), e.column());
+            eyre::Error::new(e).wrap_err(format!(
+                "Parsing {} (line {line}, column {column})",
+                path.display()
+            ))
+        })
+    }
+
+    /// Writes the profile configuration to the configuration directory
+    ///
+    /// The file is replaced atomically, as it is the only record of where the root key is stored.
+    async fn save(&self, config_dir: &Path) -> Result<()> {
+        let path = config_dir.join(PROFILE_CONFIG);
+        let contents = serde_json::to_string(self).context("Serializing the config")?;
+        mutable_file::write_atomically(&path, contents.as_bytes())
+            .await
+            .with_context(|| format!("Writing {}", path.display()))
+    }
+
+    /// Returns the path of the matrix sqlite store, given the data directory of the profile
+    #[must_use]
+    pub fn matrix_store_path(&self, data_dir: &Path) -> PathBuf {
+        data_dir.join(
+            self.store_path
+                .as_deref()
+                .unwrap_or_else(|| Path::new(MATRIX_STORE)),
+        )
+    }
+}
+
+/// State of the connection to the homeserver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The last sync succeeded
+    Connected,
+    /// The last sync failed, and is being retried
+    Reconnecting,
+    /// Not syncing, or the homeserver rejected the last sync
+    Offline,
+}
+
+/// Progress of the sync with the homeserver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncState {
+    /// The store is empty, and the first sync has not completed yet
+    InitialSyncInProgress,
+    /// The first sync has just completed
+    InitialSyncComplete,
+    /// The store is populated, and only changes are being synced
+    Incremental,
+}
+
+impl SyncState {
+    /// Returns the state after a sync response has been received
+    #[must_use]
+    pub const fn after_sync(self) -> Self {
+        match self {
+            Self::InitialSyncInProgress => Self::InitialSyncComplete,
+            Self::InitialSyncComplete | Self::Incremental => Self::Incremental,
+        }
+    }
+}
+
+/// Reason a homeserver name is invalid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomeserverNameError {
+    /// The name is empty
+    Empty,
+    /// The name contains a character that is not allowed in host names
+    InvalidCharacter(char),
+    /// The port is not a number between 0 and 65535
+    InvalidPort,
+    /// The name is a malformed IPv6 address
+    InvalidIpv6Address,
+    /// The name is invalid for another reason
+    Invalid,
+}
+
+impl std::fmt::Display for HomeserverNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "The homeserver name is empty"),
+            Self::InvalidCharacter(c) => {
+                write!(f, "The homeserver name may not contain {c:?}")
+            }
+            Self::InvalidPort => write!(f, "The port is not a number between 0 and 65535"),
+            Self::InvalidIpv6Address => write!(f, "The IPv6 address is invalid"),
+            Self::Invalid => write!(f, "Invalid homeserver name"),
+        }
+    }
+}
+
+impl std::error::Error for HomeserverNameError {}
+
+/// Error returned by operations that need the network while offline mode is enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OfflineError;
+
+impl std::fmt::Display for OfflineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Offline mode is enabled")
+    }
+}
+
+impl std::error::Error for OfflineError {}
+
+/// Error returned when the homeserver rejects a request because too many were sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitedError {
+    /// How long to wait before trying again, if the homeserver said so
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for RateLimitedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.retry_after {
+            Some(retry_after) => write!(
+                f,
+                "Too many attempts, try again in {} seconds",
+                retry_after.as_secs().max(1)
+            ),
+            None => write!(f, "Too many attempts, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitedError {}
+
+/// Writes the session of a logged-in client to a mutable file
+async fn write_session(file: &MutableFile, client: &Client) -> Result<()> {
+    let Some(AuthSession::Matrix(session)) = client.session() else {
+        return Ok(());
+    };
+    let mut data = Vec::new();
+    ciborium::ser::into_writer(&session, &mut data).context("Serializing auth data")?;
+    file.write(data).await.context("Writing auth/login")?;
+    Ok(())
+}
+
+/// Homeserver discovered from a server name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredHomeserver {
+    /// The server name the homeserver was discovered from
+    pub server_name: OwnedServerName,
+    /// Base URL of the client-server API of the homeserver
+    pub homeserver_url: Url,
+    /// Whether the homeserver answered a request for its supported versions
+    pub reachable: bool,
+}
+
+/// Backing datastore for the client
+///
+/// Files in the data directory are encrypted with keys derived from the root key of the profile, see [`open_mutable_file`](Self::open_mutable_file). The matrix store is the exception: encryption at rest is the responsibility of the database, which is given a passphrase derived from the root key, see [`ProfileConfig::store_path`].
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct DataStore {
+    /// Name of the profile
+    profile: String,
+    /// The root key for the key hierarchy.
+    root_key: KDFSecretKey,
+    /// Store holding the root key and pending rotations
+    secret_store: Arc<dyn SecretStore>,
+    /// Path to the configuration directory
+    config_dir: PathBuf,
+    /// Configuration file
+    config: RwLock<Option<ProfileConfig>>,
+    /// Path to the data directory
+    data_dir: PathBuf,
+    /// Path to the cache directory
+    cache_dir: PathBuf,
+    /// Matrix client, may not exist at startup
+    client: RwLock<Option<Arc<Client>>>,
+    /// Background sync task, if it has been started
+    sync_task: RwLock<Option<JoinHandle<()>>>,
+    /// Task persisting refreshed session tokens, if the client is logged in
+    session_task: RwLock<Option<JoinHandle<()>>>,
+    /// Notified whenever a sync touched the room list
+    rooms_changed: Arc<watch::Sender<()>>,
+    /// State of the connection to the homeserver, updated by the background sync
+    connection_state: Arc<watch::Sender<ConnectionState>>,
+    /// Progress of the sync, updated by the background sync
+    sync_state: Arc<watch::Sender<SyncState>>,
+    /// Whether network access has been disabled by the user
+    offline: AtomicBool,
+    /// Notified whenever the cached profile of the logged-in user changed
+    own_profile_changed: watch::Sender<()>,
+    /// Notified whenever the user did something
+    activity: watch::Sender<()>,
+    /// Notified whenever the user has been inactive for longer than the idle timeout
+    idle_timeout: Arc<watch::Sender<()>>,
+    /// Idle timer task, if idle logout is enabled
+    idle_task: RwLock<Option<JoinHandle<()>>>,
+}
+
+impl DataStore {
+    /// Creates a new data store
+    ///
+    /// The profile is opened in the project directories, with its root key in the secret store selected by [`ProfileConfig::secret_backend`]. Use [`DataStoreBuilder`](builder::DataStoreBuilder) to open it elsewhere, or to pass the passphrase of a secret file.
+    #[instrument]
+    pub async fn new(project_dirs: &ProjectDirs, profile: &str) -> Result<Arc<Self>> {
+        builder::DataStoreBuilder::new(project_dirs, profile)
+            .build()
+            .await
+    }
+
+    /// Returns the configuration, data and cache directories of a profile
+    pub(crate) fn profile_dirs(
+        project_dirs: &ProjectDirs,
+        profile: &str,
+    ) -> (PathBuf, PathBuf, PathBuf) {
+        let config_dir = project_dirs.config_dir().join(profile);
+        let mut data_dir = project_dirs.data_dir().join(profile);
+        let mut cache_dir = project_dirs.cache_dir().join(profile);
+
+        if data_dir == cache_dir {
+            data_dir = project_dirs.data_dir().join("data").join(profile);
+            cache_dir = project_dirs.cache_dir().join("cache").join(profile);
+        }
+        (config_dir, data_dir, cache_dir)
+    }
+
+    /// Returns true if the client is logged in
+    pub async fn is_logged_in(&self) -> bool {
+        self.with_client(|client| async move { Ok(client.logged_in()) })
+            .await
+            .unwrap_or_default()
+            .unwrap_or(false)
+    }
+
+    /// Returns true if a client has been initialized for this profile
+    pub async fn has_client(&self) -> bool {
+        self.client.read().await.is_some()
+    }
+
+    /// Runs an async closure with the client
+    ///
+    /// The client lock is not held while the closure runs, so long running closures don't block changing the homeserver or logging out. If the client is replaced in the meantime, the closure keeps using the old one.
+    ///
+    /// # Errors
+    /// This function will only return errors if the passed closure does.
+    pub async fn with_client<F, Fut, Ret>(&self, fun: F) -> Result<Option<Ret>>
+    where
+        F: FnOnce(Arc<Client>) -> Fut + Send,
+        Fut: Future<Output = Result<Ret>> + Send,
+    {
+        let client = self.client.read().await.clone();
+        let Some(client) = client else {
+            return Ok(None);
+        };
+        Ok(Some(
+            fun(client)
+                .await
+                .context("Running a closure with the client")?,
+        ))
+    }
+
+    /// Returns the client, if it is logged in
+    async fn logged_in_client(&self) -> Result<Arc<Client>> {
+        self.client
+            .read()
+            .await
+            .clone()
+            .filter(|client| client.logged_in())
+            .ok_or_else(|| eyre::eyre!("Not logged in"))
+    }
+
+    /// Enables or disables offline mode
+    ///
+    /// While offline mode is enabled, the background sync is paused, and operations that need the network fail with [`OfflineError`] or fall back to cached data.
+    pub async fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+        if offline {
+            self.stop_sync().await;
+        } else if self.is_logged_in().await {
+            self.start_sync().await;
+        }
+    }
+
+    /// Returns true if offline mode is enabled
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Returns an [`OfflineError`] if offline mode is enabled
+    fn ensure_online(&self) -> Result<()> {
+        if self.is_offline() {
+            return Err(OfflineError.into());
+        }
+        Ok(())
+    }
+
+    /// Returns whether a name is a valid homeserver name
+    #[must_use]
+    pub fn is_valid_homeserver_name(server_name: impl AsRef<str>) -> bool {
+        Self::validate_homeserver_name(server_name).is_ok()
+    }
+
+    /// Parses a homeserver name, describing what is wrong with it if it is invalid
+    ///
+    /// # Errors
+    /// This function returns an error if the name is not a valid server name.
+    pub fn validate_homeserver_name(
+        server_name: impl AsRef<str>,
+    ) -> Result<OwnedServerName, HomeserverNameError> {
+        let server_name = server_name.as_ref();
+        if server_name.is_empty() {
+            return Err(HomeserverNameError::Empty);
+        }
+        let (host, port) = if let Some(rest) = server_name.strip_prefix('[') {
+            let Some((address, port)) = rest.split_once(']') else {
+                return Err(HomeserverNameError::InvalidIpv6Address);
+            };
+            if address.parse::<std::net::Ipv6Addr>().is_err() {
+                return Err(HomeserverNameError::InvalidIpv6Address);
+            }
+            if !port.is_empty() && !port.starts_with(':') {
+                return Err(HomeserverNameError::InvalidPort);
+            }
+            ("", port.strip_prefix(':'))
+        } else {
+            server_name
+                .split_once(':')
+                .map_or((server_name, None), |(host, port)| (host, Some(port)))
+        };
+        if let Some(c) = host
+            .chars()
+            .find(|&c| !(c.is_ascii_alphanumeric() || c == '-' || c == '.'))
+        {
+            return Err(HomeserverNameError::InvalidCharacter(c));
+        }
+        if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+            return Err(HomeserverNameError::InvalidPort);
+        }
+        ServerName::parse(server_name).map_err(|_| HomeserverNameError::Invalid)
+    }
+
+    /// Extracts the homeserver name from what a user entered as their homeserver, and validates it
+    ///
+    /// Besides plain server names, this accepts homeserver URLs like `https://matrix.org/` and user IDs like `@alice:matrix.org`. Surrounding whitespace is ignored, and the name is lowercased.
+    ///
+    /// # Errors
+    /// This function returns an error if no valid server name can be extracted.
+    pub fn normalize_homeserver_input(
+        input: impl AsRef<str>,
+    ) -> Result<OwnedServerName, HomeserverNameError> {
+        let input = input.as_ref().trim();
+        let server_name = input.strip_prefix('@').map_or_else(
+            || {
+                let without_scheme = input.split_once("://").map_or(input, |(_, rest)| rest);
+                without_scheme
+                    .split(['/', '?', '#'])
+                    .next()
+                    .unwrap_or_default()
+            },
+            |user_id| {
+                user_id
+                    .split_once(':')
+                    .map_or("", |(_, server_name)| server_name)
+            },
+        );
+        Self::validate_homeserver_name(server_name.to_ascii_lowercase())
+    }
+
+    /// Discovers the homeserver for a server name
+    ///
+    /// This performs the same `.well-known` lookup as [`set_homeserver`](Self::set_homeserver), without opening a store or touching the profile configuration.
+    ///
+    /// # Errors
+    /// This function returns an error if the server name is invalid or if no homeserver could be discovered for it.
+    pub async fn discover_homeserver(
+        server_name: impl AsRef<str> + Send,
+    ) -> Result<DiscoveredHomeserver> {
+        let server_name = ServerName::parse(&server_name)
+            .with_context(|| format!("Parsing server name: {}", server_name.as_ref()))?;
+        let client = Client::builder()
+            .server_name(&server_name)
+            .user_agent("rachat")
+            .build()
+            .await
+            .with_context(|| format!("Discovering the homeserver of {server_name}"))?;
+        let reachable = match client
+            .send(get_supported_versions::Request::new(), None)
+            .await
+        {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Homeserver of {server_name} is not reachable: {e:#?}");
+                false
+            }
+        };
+        Ok(DiscoveredHomeserver {
+            server_name,
+            homeserver_url: client.homeserver(),
+            reachable,
+        })
+    }
+
+    /// Removes the homeserver for this profile
+    ///
+    /// Only the server name is removed from the profile configuration. The other settings, in particular where the root key is stored, are kept for the next homeserver.
+    ///
+    /// # Errors
+    /// This function returns an error if deleting associated configuration data fails.
+    pub async fn reset_homeserver(&self) -> Result<()> {
+        self.cancel_idle_logout().await;
+        self.stop_sync().await;
+        self.stop_session_listener().await;
+        *self.client.write().await = None;
+        let mut config = self.config.write().await;
+        if let Some(config) = config.as_mut() {
+            config.server_name = None;
+            config
+                .save(&self.config_dir)
+                .await
+                .context("Updating the config")?;
+        }
+        drop(config);
+        self.root_key
+            .open_mutable_file(&self.data_dir, "auth/login")
+            .delete()
+            .await
+            .context("Deleting auth/login")?;
+        self.open_mutable_file(profile::OWN_PROFILE)
+            .delete()
+            .await
+            .context("Deleting the cached profile")?;
+        self.own_profile_changed.send_replace(());
+        Ok(())
+    }
+
+    /// Sets the homeserver for this profile
+    ///
+    /// # Errors
+    /// This function will return an error in the following cases:
+    ///
+    /// - The homeserver name is invalid
+    /// - The homeserver is invalid or not online
+    /// - Existing session data could not be loaded from disk
+    /// - The profile configuration file could not be updated
+    pub async fn set_homeserver(
+        self: Arc<Self>,
+        server_name: impl AsRef<str> + Send,
+    ) -> Result<()> {
+        let server_name = ServerName::parse(&server_name)
+            .with_context(|| format!("Parsing server name: {}", server_name.as_ref()))?;
+        let store_path = self.config.read().await.as_ref().map_or_else(
+            || self.data_dir.join(MATRIX_STORE),
+            |config| config.matrix_store_path(&self.data_dir),
+        );
+
+        let secret = self
+            .matrix_store_passphrase()
+            .await
+            .context("Obtaining the matrix store passphrase")?;
+
+        let client = Client::builder()
+            .server_name(server_name.as_ref())
+            .sqlite_store(store_path, Some(secret.expose_secret().as_str()))
+            .user_agent("rachat")
+            .handle_refresh_tokens()
+            .build()
+            .await
+            .context("Building the client")?;
+        self.attach_client(server_name, client).await
+    }
+
+    /// Makes a client for the homeserver of `server_name` the client of this profile
+    ///
+    /// The server name is stored in the profile configuration, and the login session is restored into the client.
+    async fn attach_client(&self, server_name: OwnedServerName, client: Client) -> Result<()> {
+        let mut config = self.config.write().await;
+        config
+            .get_or_insert_with(ProfileConfig::default)
+            .server_name = Some(server_name);
+
+        // Restore the login session if it exists
+        match self
+            .root_key
+            .open_mutable_file(&self.data_dir, "auth/login")
+            .read()
+            .await
+            .context("Reading auth/login")
+        {
+            Ok(Some(session_data)) => {
+                let client_session: MatrixSession =
+                    ciborium::de::from_reader(session_data.as_slice())
+                        .context("Deserializing auth/login")?;
+                client
+                    .restore_session(client_session)
+                    .await
+                    .context("Restoring matrix session")?;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to read auth/login: {e:#?}");
+                self.root_key
+                    .open_mutable_file(&self.data_dir, "auth/login")
+                    .delete()
+                    .await
+                    .context("Deleting auth/login")?;
+            }
+        }
+
+        self.stop_sync().await;
+        self.stop_session_listener().await;
+        let logged_in = client.logged_in();
+        *self.client.write().await = Some(Arc::new(client));
+        if logged_in {
+            self.start_sync().await;
+            self.start_session_listener().await;
+        }
+
+        if let Some(config) = config.as_ref() {
+            config
+                .save(&self.config_dir)
+                .await
+                .context("Updating the config")?;
+        }
+
+        drop(config);
+        self.schedule_idle_logout().await;
+        Ok(())
+    }
+
+    /// Writes the session of the client to disk
+    async fn persist_session(&self) -> Result<()> {
+        let client = self.client.read().await.clone();
+        let Some(client) = client else {
+            return Ok(());
+        };
+        write_session(
+            &self
+                .root_key
+                .open_mutable_file(&self.data_dir, "auth/login"),
+            &client,
+        )
+        .await
+    }
+
+    /// Starts writing the session to disk whenever the client refreshes its tokens
+    ///
+    /// The listener only holds weak references, so that it keeps neither the data store nor the client alive. It is stopped whenever the client is replaced.
+    async fn start_session_listener(&self) {
+        self.stop_session_listener().await;
+        let client = self.client.read().await.clone();
+        let Some(client) = client else {
+            return;
+        };
+        let Some(changes) = client.matrix_auth().session_tokens_changed_stream() else {
+            return;
+        };
+        let client = Arc::downgrade(&client);
+        let file = self
+            .root_key
+            .open_mutable_file(&self.data_dir, "auth/login");
+        *self.session_task.write().await = Some(tokio::spawn(async move {
+            let mut changes = std::pin::pin!(changes);
+            while changes.next().await.is_some() {
+                let Some(client) = client.upgrade() else {
+                    return;
+                };
+                if let Err(e) = write_session(&file, &client).await {
+                    error!("Failed to persist session: {e:#?}");
+                }
+            }
+        }));
+    }
+
+    /// Stops the session listener, waiting until it has released the client
+    async fn stop_session_listener(&self) {
+        let task = self.session_task.write().await.take();
+        if let Some(task) = task {
+            task.abort();
+            // The task has been aborted, so this only waits for it to be dropped
+            let _ = task.await;
+        }
+    }
+
+    /// Returns the passphrase of the matrix store
+    ///
+    /// This is derived from the root key, unless the root key has been rotated, in which case the original passphrase is stored in a mutable file.
+    async fn matrix_store_passphrase(&self) -> Result<Secret<String>> {
+        let stored = self
+            .open_mutable_file(MATRIX_STORE_PASSPHRASE)
+            .read()
+            .await
+            .context("Reading the stored matrix store passphrase")?;
+        match stored {
+            Some(passphrase) => Ok(Secret::new(
+                String::from_utf8(passphrase).context("Decoding the matrix store passphrase")?,
+            )),
+            None => Ok(self.root_key.subkey_passphrase(Purpose::MatrixSdk)),
+        }
+    }
+
+    /// Schedules a rotation of the root key of this profile
+    ///
+    /// The rotation is performed the next time the data store is opened, before any data is read.
+    ///
+    /// # Errors
+    /// This function returns an error if accessing the secret store fails.
+    pub async fn schedule_root_key_rotation(&self) -> Result<()> {
+        KDFSecretKey::schedule_rotation(self.secret_store.as_ref(), &self.profile).await
+    }
+
+    /// Returns a handle to a mutable data file
+    ///
+    /// This data will be encrypted on disk
+    pub fn open_mutable_file(&self, path: impl AsRef<Path>) -> MutableFile {
+        self.root_key.open_mutable_file(&self.data_dir, path)
+    }
+
+    /// Logins a user to a homeserver
+    pub async fn login(
+        &self,
+        username: impl AsRef<str> + Send,
+        password: impl AsRef<str> + Send,
+    ) -> Result<()> {
+        self.with_client(|client| async move {
+            let response = client
+                .matrix_auth()
+                .login_username(username.as_ref(), password.as_ref())
+                .request_refresh_token()
+                .send()
+                .await
+                .context("Logging into matrix")?;
+            info!(
+                "Logged in as {}, got device_id {}",
+                username.as_ref(),
+                response.device_id,
+            );
+            Ok(())
+        })
+        .await
+        .map(|_| ())?;
+        self.finish_login().await
+    }
+
+    /// Checks whether a username and password are valid on the selected homeserver
+    ///
+    /// This logs into a throwaway client and logs out again right away, so the session of the data store is not touched and no sync is started. Returns `false` if the homeserver rejected the credentials.
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, no homeserver has been selected or the homeserver could not be reached. If the homeserver is rate limiting login attempts, the error is a [`RateLimitedError`].
+    pub async fn validate_credentials(
+        &self,
+        username: impl AsRef<str> + Send,
+        password: impl AsRef<str> + Send,
+    ) -> Result<bool> {
+        self.ensure_online()?;
+        let homeserver = self
+            .client
+            .read()
+            .await
+            .as_ref()
+            .ok_or_else(|| eyre::eyre!("No homeserver has been selected"))?
+            .homeserver();
+        let client = Client::builder()
+            .homeserver_url(homeserver)
+            .build()
+            .await
+            .context("Creating a client for checking the credentials")?;
+        let result = client
+            .matrix_auth()
+            .login_username(username.as_ref(), password.as_ref())
+            .initial_device_display_name("rachat (checking credentials)")
+            .send()
+            .await;
+        match result {
+            Ok(_) => {
+                if let Err(e) = client.matrix_auth().logout().await {
+                    warn!(
+                        "Failed to log out the session used for checking the credentials: {e:#?}"
+                    );
+                }
+                Ok(true)
+            }
+            Err(e) => match e.client_api_error_kind() {
+                Some(ErrorKind::Forbidden) => Ok(false),
+                Some(ErrorKind::LimitExceeded { retry_after_ms }) => Err(RateLimitedError {
+                    retry_after: *retry_after_ms,
+                }
+                .into()),
+                _ => Err(eyre::Report::new(e).wrap_err("Checking the credentials")),
+            },
+        }
+    }
+
+    /// Logs out of the homeserver, removing the session and the matrix store
+    ///
+    /// The encryption state in the matrix store belongs to the device that was logged out, so its databases are deleted and a fresh client is prepared for the next login. If offline mode is enabled, the session is only removed locally.
+    ///
+    /// # Errors
+    /// This function returns an error if the session or the store could not be deleted, or if the new client could not be prepared.
+    pub async fn logout(self: Arc<Self>) -> Result<()> {
+        self.cancel_idle_logout().await;
+        self.stop_sync().await;
+        self.stop_session_listener().await;
+        let client = self.client.write().await.take();
+        if let Some(client) = client.as_ref().filter(|client| client.logged_in()) {
+            if self.is_offline() {
+                warn!("Offline mode is enabled, the session is only removed locally");
+            } else if let Err(e) = client.matrix_auth().logout().await {
+                warn!("Failed to end the session on the homeserver: {e:#?}");
+            }
+        }
+        // The sync loop and the session listener are stopped, so this closes the connections to the matrix store
+        drop(client);
+        self.root_key
+            .open_mutable_file(&self.data_dir, "auth/login")
+            .delete()
+            .await
+            .context("Deleting auth/login")?;
+        self.open_mutable_file(profile::OWN_PROFILE)
+            .delete()
+            .await
+            .context("Deleting the cached profile")?;
+        self.own_profile_changed.send_replace(());
+
+        let Some(config) = self.config.read().await.clone() else {
+            return Ok(());
+        };
+        let Some(server_name) = config.server_name.clone() else {
+            return Ok(());
+        };
+        maintenance::remove_store(&config.matrix_store_path(&self.data_dir))
+            .await
+            .context("Deleting the matrix store")?;
+        info!("Logged out of {server_name}");
+        self.set_homeserver(server_name)
+            .await
+            .context("Preparing a new client")
+    }
+
+    /// Logs a user into the homeserver using single sign-on
+    ///
+    /// `on_url` is called with the URL the user has to open in a browser. The SDK starts a local server to receive the redirect from the homeserver, and this function returns once it has been received.
+    ///
+    /// # Errors
+    /// This function returns an error if the login fails or the session could not be persisted.
+    pub async fn login_sso<F>(&self, on_url: F) -> Result<()>
+    where
+        F: FnOnce(Url) + Send,
+    {
+        self.with_client(|client| async move {
+            let response = client
+                .matrix_auth()
+                .login_sso(|url| async move {
+                    on_url(Url::parse(&url)?);
+                    Ok(())
+                })
+                .request_refresh_token()
+                .send()
+                .await
+                .context("Logging into matrix using SSO")?;
+            info!(
+                "Logged in as {} using SSO, got device_id {}",
+                response.user_id, response.device_id,
+            );
+            Ok(())
+        })
+        .await
+        .map(|_| ())?;
+        self.finish_login().await
+    }
+
+    /// Persists a fresh login session, starts syncing and fetches the profile of the user
+    async fn finish_login(&self) -> Result<()> {
+        self.persist_session()
+            .await
+            .context("Persisting fresh login session")?;
+        self.start_sync().await;
+        self.start_session_listener().await;
+        self.schedule_idle_logout().await;
+        if let Err(e) = self.refresh_own_profile().await {
+            warn!("Failed to fetch the profile: {e:#?}");
+        }
+        Ok(())
+    }
+
+    /// Starts syncing with the homeserver in the background
+    ///
+    /// Failed syncs are retried with exponential backoff. This does nothing if there is no client, if offline mode is enabled, or if the sync is already running.
+    pub async fn start_sync(&self) {
+        let mut sync_task = self.sync_task.write().await;
+        self.start_sync_locked(&mut sync_task).await;
+    }
+
+    /// Starts syncing with the homeserver in the background, while the sync task is locked
+    async fn start_sync_locked(&self, sync_task: &mut Option<JoinHandle<()>>) {
+        if self.is_offline() {
+            return;
+        }
+        if sync_task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+        let Some(client) = self.client.read().await.clone() else {
+            return;
+        };
+        // A sync token is only stored once a sync completed, so the store is already populated
+        let has_synced = client
+            .store()
+            .get_kv_data(StateStoreDataKey::SyncToken)
+            .await
+            .is_ok_and(|token| token.is_some());
+        self.sync_state.send_replace(if has_synced {
+            SyncState::Incremental
+        } else {
+            SyncState::InitialSyncInProgress
+        });
+        *sync_task = Some(tokio::spawn(Self::sync_loop(
+            client,
+            Arc::clone(&self.rooms_changed),
+            Arc::clone(&self.connection_state),
+            Arc::clone(&self.sync_state),
+        )));
+    }
+
+    /// Stops the background sync, if it is running
+    ///
+    /// This waits until the sync loop has released the client.
+    pub async fn stop_sync(&self) {
+        let mut sync_task = self.sync_task.write().await;
+        self.stop_sync_locked(&mut sync_task).await;
+    }
+
+    /// Stops the background sync, while the sync task is locked
+    async fn stop_sync_locked(&self, sync_task: &mut Option<JoinHandle<()>>) {
+        if let Some(task) = sync_task.take() {
+            task.abort();
+            // The task has been aborted, so this only waits for it to be dropped
+            let _ = task.await;
+        }
+        self.connection_state.send_replace(ConnectionState::Offline);
+    }
+
+    /// Stops the background tasks and persists the session one last time
+    pub async fn shutdown(&self) {
+        self.stop_sync().await;
+        self.stop_session_listener().await;
+        self.cancel_idle_logout().await;
+        if self.is_logged_in().await {
+            if let Err(e) = self.persist_session().await {
+                error!("Failed to persist session: {e:#?}");
+            }
+        }
+    }
+
+    /// Returns true if the background sync is running
+    pub async fn is_syncing(&self) -> bool {
+        self.sync_task
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+    }
+
+    /// Returns the state of the connection to the homeserver
+    #[must_use]
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.connection_state.borrow()
+    }
+
+    /// Returns a stream of connection states, yielding whenever the state changes
+    ///
+    /// The current state is yielded first.
+    pub fn watch_connection_state(&self) -> impl Stream<Item = ConnectionState> {
+        let mut receiver = self.connection_state.subscribe();
+        receiver.mark_changed();
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.changed().await.ok()?;
+            let state = *receiver.borrow_and_update();
+            Some((state, receiver))
+        })
+    }
+
+    /// Returns the progress of the sync
+    ///
+    /// This is only meaningful while the background sync is running.
+    #[must_use]
+    pub fn sync_state(&self) -> SyncState {
+        *self.sync_state.borrow()
+    }
+
+    /// Returns a stream of sync states, yielding whenever the state changes
+    ///
+    /// The current state is yielded first. The frontend can show a spinner while it is [`SyncState::InitialSyncInProgress`].
+    pub fn watch_sync_state(&self) -> impl Stream<Item = SyncState> {
+        let mut receiver = self.sync_state.subscribe();
+        receiver.mark_changed();
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.changed().await.ok()?;
+            let state = *receiver.borrow_and_update();
+            Some((state, receiver))
+        })
+    }
+
+    /// Syncs with the homeserver until the task is aborted
+    async fn sync_loop(
+        client: Arc<Client>,
+        rooms_changed: Arc<watch::Sender<()>>,
+        connection_state: Arc<watch::Sender<ConnectionState>>,
+        sync_state: Arc<watch::Sender<SyncState>>,
+    ) {
+        let mut backoff = SYNC_MIN_BACKOFF;
+        loop {
+            let synced = AtomicBool::new(false);
+            let result = client
+                .sync_with_callback(SyncSettings::default().timeout(SYNC_TIMEOUT), |response| {
+                    synced.store(true, Ordering::Relaxed);
+                    connection_state.send_replace(ConnectionState::Connected);
+                    sync_state.send_if_modified(|state| {
+                        let next = state.after_sync();
+                        let changed = next != *state;
+                        *state = next;
+                        changed
+                    });
+                    let rooms = &response.rooms;
+                    if !rooms.join.is_empty() || !rooms.leave.is_empty() || !rooms.invite.is_empty()
+                    {
+                        rooms_changed.send_replace(());
+                    }
+                    async { LoopCtrl::Continue }
+                })
+                .await;
+            if synced.load(Ordering::Relaxed) {
+                backoff = SYNC_MIN_BACKOFF;
+            }
+            if let Err(e) = result {
+                warn!("Sync failed, retrying in {backoff:?}: {e:#?}");
+                // Errors without a matrix error code come from the network or a proxy, and are likely to go away
+                let state = match e.client_api_error_kind() {
+                    None | Some(ErrorKind::LimitExceeded { .. }) => ConnectionState::Reconnecting,
+                    Some(_) => ConnectionState::Offline,
+                };
+                connection_state.send_replace(state);
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(SYNC_MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use matrix_sdk::ServerName;
+    use secrecy::Secret;
+
+    use super::{DataStore, HomeserverNameError, ProfileConfig, SecretBackend, SyncState};
+    use crate::test_utils::{builder, local_client, TempDir};
+
+    #[test]
+    fn test_sync_state_after_sync() {
+        let mut state = SyncState::InitialSyncInProgress;
+        state = state.after_sync();
+        assert_eq!(state, SyncState::InitialSyncComplete);
+        state = state.after_sync();
+        assert_eq!(state, SyncState::Incremental);
+        assert_eq!(state.after_sync(), SyncState::Incremental);
+    }
+
+    #[test]
+    fn test_validate_homeserver_name() {
+        assert!(DataStore::validate_homeserver_name("matrix.org").is_ok());
+        assert!(DataStore::validate_homeserver_name("example.com:8448").is_ok());
+        assert!(DataStore::validate_homeserver_name("[::1]:8448").is_ok());
+        assert_eq!(
+            DataStore::validate_homeserver_name(""),
+            Err(HomeserverNameError::Empty)
+        );
+        assert_eq!(
+            DataStore::validate_homeserver_name("example.com:http"),
+            Err(HomeserverNameError::InvalidPort)
+        );
+        assert_eq!(
+            DataStore::validate_homeserver_name("example.com:65536"),
+            Err(HomeserverNameError::InvalidPort)
+        );
+        assert_eq!(
+            DataStore::validate_homeserver_name("exa mple.com"),
+            Err(HomeserverNameError::InvalidCharacter(' '))
+        );
+        assert_eq!(
+            DataStore::validate_homeserver_name("[::g]"),
+            Err(HomeserverNameError::InvalidIpv6Address)
+        );
+        assert!(!DataStore::is_valid_homeserver_name("@user:example.com"));
+    }
+
+    #[test]
+    fn test_normalize_homeserver_input() -> eyre::Result<()> {
+        for input in [
+            "matrix.org",
+            " matrix.org\n",
+            "https://matrix.org/",
+            "https://matrix.org/_matrix/client/versions?x=1",
+            "Matrix.ORG",
+            "@alice:matrix.org",
+        ] {
+            assert_eq!(
+                DataStore::normalize_homeserver_input(input)?.as_str(),
+                "matrix.org",
+                "{input:?}"
+            );
+        }
+        assert_eq!(
+            DataStore::normalize_homeserver_input("matrix.org:8448")?.as_str(),
+            "matrix.org:8448"
+        );
+        assert_eq!(
+            DataStore::normalize_homeserver_input("https://[::1]:8448/")?.as_str(),
+            "[::1]:8448"
+        );
+        assert_eq!(
+            DataStore::normalize_homeserver_input("@alice"),
+            Err(HomeserverNameError::Empty)
+        );
+        assert_eq!(
+            DataStore::normalize_homeserver_input("https://"),
+            Err(HomeserverNameError::Empty)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_path_defaults_to_data_dir() -> eyre::Result<()> {
+        let config: ProfileConfig = serde_json::from_str(r#"{"server_name":"example.com"}"#)?;
+        assert_eq!(config.store_path, None);
+        assert_eq!(
+            config.matrix_store_path(Path::new("/data")),
+            Path::new("/data/matrix.db")
+        );
+
+        let config: ProfileConfig =
+            serde_json::from_str(r#"{"server_name":"example.com","store_path":"/mnt/store.db"}"#)?;
+        assert_eq!(
+            config.matrix_store_path(Path::new("/data")),
+            Path::new("/mnt/store.db")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset_homeserver_keeps_the_secret_backend() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.join("config"))?;
+        std::fs::write(
+            dir.join("config/config.json"),
+            r#"{"server_name":"example.com","secret_backend":"file","idle_logout_secs":30}"#,
+        )?;
+        let open = |client| {
+            builder(&dir)
+                .secret_passphrase(Secret::new("correct horse battery staple".to_owned()))
+                .client(client)
+        };
+        let read_config = || -> eyre::Result<ProfileConfig> {
+            Ok(serde_json::from_str(&std::fs::read_to_string(
+                dir.join("config/config.json"),
+            )?)?)
+        };
+
+        let data_store = open(local_client().await?).build().await?;
+        data_store.open_mutable_file("test").write("data").await?;
+        data_store.reset_homeserver().await?;
+        assert_eq!(read_config()?.server_name, None);
+        assert_eq!(read_config()?.secret_backend, SecretBackend::File);
+
+        // This is the part of set_homeserver that runs once the client has been discovered
+        data_store
+            .attach_client(ServerName::parse("example.org")?, local_client().await?)
+            .await?;
+        drop(data_store);
+        let config = read_config()?;
+        assert_eq!(
+            config.server_name.as_deref().map(ServerName::as_str),
+            Some("example.org")
+        );
+        assert_eq!(config.secret_backend, SecretBackend::File);
+        assert_eq!(config.idle_logout_secs, Some(30));
+
+        // The root key is still read from the secret file
+        let data_store = open(local_client().await?).build().await?;
+        let file = data_store.open_mutable_file("test");
+        assert_eq!(file.read().await?.as_deref(), Some(&b"data"[..]));
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//! Profile of the logged-in user
+//!
+//! The profile is cached in an encrypted mutable file, so that it is available before the first sync.
+
+use std::sync::Arc;
+
+use eyre::{Context, Result};
+use futures::{stream, Stream};
+use matrix_sdk::ruma::{OwnedMxcUri, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+use super::DataStore;
+
+/// Mutable file the profile of the logged-in user is cached in
+pub(super) const OWN_PROFILE: &str = "profile/own";
+
+/// Profile of the logged-in user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnProfile {
+    /// ID of the user
+    pub user_id: OwnedUserId,
+    /// Display name of the user, if one is set
+    pub display_name: Option<String>,
+    /// MXC URL of the avatar of the user, if one is set
+    pub avatar_url: Option<OwnedMxcUri>,
+}
+
+impl DataStore {
+    /// Returns the cached profile of the logged-in user
+    ///
+    /// Returns `None` if the profile has not been fetched yet.
+    ///
+    /// # Errors
+    /// This function returns an error if the cached profile could not be read.
+    pub async fn own_profile(&self) -> Result<Option<OwnProfile>> {
+        let Some(data) = self
+            .open_mutable_file(OWN_PROFILE)
+            .read()
+            .await
+            .context("Reading the cached profile")?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(
+            ciborium::de::from_reader(data.as_slice()).context("Parsing the cached profile")?,
+        ))
+    }
+
+    /// Fetches the profile of the logged-in user from the homeserver and updates the cache
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in, the profile could not be fetched, or the cache could not be updated.
+    pub async fn refresh_own_profile(&self) -> Result<OwnProfile> {
+        self.ensure_online()?;
+        let client = self
+            .client
+            .read()
+            .await
+            .clone()
+            .ok_or_else(|| eyre::eyre!("Not logged in"))?;
+        let user_id = client
+            .user_id()
+            .ok_or_else(|| eyre::eyre!("Not logged in"))?
+            .to_owned();
+        let response = client
+            .account()
+            .get_profile()
+            .await
+            .context("Fetching the profile")?;
+        let profile = OwnProfile {
+            user_id,
+            display_name: response.displayname,
+            avatar_url: response.avatar_url,
+        };
+
+        if self.own_profile().await.ok().flatten().as_ref() != Some(&profile) {
+            let mut data = Vec::new();
+            ciborium::ser::into_writer(&profile, &mut data).context("Serializing the profile")?;
+            self.open_mutable_file(OWN_PROFILE)
+                .write(data)
+                .await
+                .context("Caching the profile")?;
+            self.own_profile_changed.send_replace(());
+        }
+        Ok(profile)
+    }
+
+    /// Returns a stream of the profile of the logged-in user, yielding whenever it changes
+    ///
+    /// The current profile is yielded first.
+    pub fn watch_own_profile(self: Arc<Self>) -> impl Stream<Item = Option<OwnProfile>> {
+        let mut receiver = self.own_profile_changed.subscribe();
+        receiver.mark_changed();
+        stream::unfold((self, receiver), |(data_store, mut receiver)| async move {
+            receiver.changed().await.ok()?;
+            let profile = data_store.own_profile().await.ok().flatten();
+            Some((profile, (data_store, receiver)))
+        })
+    }
+}
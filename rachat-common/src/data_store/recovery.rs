@@ -0,0 +1,98 @@
+//! Cross-signing and recovery of encryption keys
+//!
+//! Recovery stores the cross-signing keys and the key backup key in secret storage on the homeserver, encrypted with a recovery key that only the user knows. Nothing beyond what the matrix store already holds is written to disk.
+
+use std::future::IntoFuture;
+
+use eyre::{Context, Result};
+use futures::StreamExt;
+use matrix_sdk::encryption::recovery::EnableProgress;
+use secrecy::Secret;
+use tracing::warn;
+
+use super::DataStore;
+
+/// Progress of enabling recovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryProgress {
+    /// A new server-side key backup is being created
+    CreatingBackup,
+    /// A new recovery key is being created, and the secrets are being uploaded
+    CreatingRecoveryKey,
+    /// Room keys are being uploaded to the key backup
+    BackingUp {
+        /// Number of room keys that have been backed up
+        backed_up: usize,
+        /// Total number of room keys
+        total: usize,
+    },
+    /// Not all room keys could be uploaded, they are retried later
+    BackupFailed,
+}
+
+impl DataStore {
+    /// Sets up cross-signing and recovery, returning the recovery key
+    ///
+    /// The recovery key has to be shown to the user to be written down, it is not stored anywhere. `on_progress` is called as room keys are backed up.
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in, cross-signing could not be set up, or recovery could not be enabled.
+    pub async fn enable_recovery<F>(&self, mut on_progress: F) -> Result<Secret<String>>
+    where
+        F: FnMut(RecoveryProgress) + Send,
+    {
+        self.ensure_online()?;
+        let client = self.logged_in_client().await?;
+        let encryption = client.encryption();
+        encryption
+            .bootstrap_cross_signing_if_needed(None)
+            .await
+            .context("Setting up cross-signing")?;
+
+        let recovery = encryption.recovery();
+        let enable = recovery.enable().wait_for_backups_to_upload();
+        let mut progress = enable.subscribe_to_progress();
+        let enable = enable.into_future();
+        tokio::pin!(enable);
+        let recovery_key = loop {
+            tokio::select! {
+                result = &mut enable => break result.context("Enabling recovery")?,
+                Some(update) = progress.next() => {
+                    let update = match update.as_ref() {
+                        Ok(EnableProgress::CreatingBackup) => RecoveryProgress::CreatingBackup,
+                        Ok(EnableProgress::CreatingRecoveryKey) => {
+                            RecoveryProgress::CreatingRecoveryKey
+                        }
+                        Ok(EnableProgress::BackingUp(counts)) => RecoveryProgress::BackingUp {
+                            backed_up: counts.backed_up,
+                            total: counts.total,
+                        },
+                        Ok(EnableProgress::RoomKeyUploadError) => RecoveryProgress::BackupFailed,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            warn!("Missed recovery progress updates: {e:#?}");
+                            continue;
+                        }
+                    };
+                    on_progress(update);
+                }
+            }
+        };
+        Ok(Secret::new(recovery_key))
+    }
+
+    /// Restores cross-signing and the key backup on this device using the recovery key
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in, or if the recovery key is wrong.
+    pub async fn restore_recovery(&self, recovery_key: &str) -> Result<()> {
+        self.ensure_online()?;
+        self.logged_in_client()
+            .await?
+            .encryption()
+            .recovery()
+            .recover(recovery_key)
+            .await
+            .context("Recovering the encryption keys")
+    }
+}
@@ -0,0 +1,324 @@
+//! Registration of new accounts
+//!
+//! Registration uses user-interactive authentication. Stages that need no input from the user are completed automatically, all others are returned to the caller as a [`PendingRegistration`] so that the user can be prompted for them.
+
+use std::fmt;
+
+use eyre::{Context, Result};
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{
+        api::client::{
+            account::{register, request_registration_token_via_email},
+            error::ErrorKind,
+            uiaa::{self, AuthData, AuthType, UiaaInfo},
+        },
+        ClientSecret, OwnedClientSecret, OwnedSessionId, UInt,
+    },
+    Client, HttpError, SessionMeta,
+};
+use tracing::info;
+
+use super::DataStore;
+
+/// Error returned when registering an account fails
+#[derive(Debug)]
+pub enum RegistrationError {
+    /// The username is already in use
+    UsernameTaken,
+    /// The homeserver does not allow registering new accounts
+    RegistrationDisabled,
+    /// The homeserver requires further authentication stages to be completed
+    AdditionalAuthRequired(PendingRegistration),
+    /// Any other error
+    Other(eyre::Report),
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UsernameTaken => write!(f, "The username is already taken"),
+            Self::RegistrationDisabled => write!(f, "Registration is disabled on this homeserver"),
+            Self::AdditionalAuthRequired(pending) => write!(
+                f,
+                "Additional authentication is required: {}",
+                pending
+                    .next_stages()
+                    .iter()
+                    .map(AuthType::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {}
+
+impl From<eyre::Report> for RegistrationError {
+    fn from(e: eyre::Report) -> Self {
+        Self::Other(e)
+    }
+}
+
+/// A registration that is waiting for further authentication stages
+#[derive(Debug, Clone)]
+pub struct PendingRegistration {
+    /// Information about the authentication flows returned by the homeserver
+    info: UiaaInfo,
+    /// Session id and client secret of the email validation, if a validation email was sent
+    email_credentials: Option<(OwnedSessionId, OwnedClientSecret)>,
+}
+
+impl PendingRegistration {
+    /// Returns the available authentication flows, with the already completed stages removed
+    #[must_use]
+    pub fn remaining_flows(&self) -> Vec<Vec<AuthType>> {
+        self.info
+            .flows
+            .iter()
+            .map(|flow| {
+                flow.stages
+                    .iter()
+                    .filter(|stage| !self.info.completed.contains(stage))
+                    .cloned()
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Returns the stages that can be completed next
+    #[must_use]
+    pub fn next_stages(&self) -> Vec<AuthType> {
+        let mut stages = Vec::new();
+        for flow in self.remaining_flows() {
+            if let Some(stage) = flow.into_iter().next() {
+                if !stages.contains(&stage) {
+                    stages.push(stage);
+                }
+            }
+        }
+        stages
+    }
+
+    /// Returns the parameters of an authentication stage, like the public key for `m.login.recaptcha`
+    #[must_use]
+    pub fn params(&self, stage: &AuthType) -> Option<serde_json::Value> {
+        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(self.info.params.get())
+            .ok()?
+            .remove(stage.as_str())
+    }
+
+    /// Returns whether a validation email has been sent
+    #[must_use]
+    pub const fn email_sent(&self) -> bool {
+        self.email_credentials.is_some()
+    }
+
+    /// Returns the authentication data for a solved captcha
+    #[must_use]
+    pub fn recaptcha(&self, response: impl Into<String>) -> AuthData {
+        let mut auth = uiaa::ReCaptcha::new(response.into());
+        auth.session.clone_from(&self.info.session);
+        AuthData::ReCaptcha(auth)
+    }
+
+    /// Returns the authentication data for a validated email address
+    ///
+    /// This returns `None` if no validation email has been sent.
+    #[must_use]
+    pub fn email_identity(&self) -> Option<AuthData> {
+        let (sid, client_secret) = self.email_credentials.as_ref()?;
+        // The homeserver validated the address itself, so there is no identity server to name. The fields are still required by ruma, and homeservers treat an empty identity server as absent.
+        let mut data = serde_json::Map::new();
+        data.insert(
+            "threepid_creds".to_owned(),
+            serde_json::json!({
+                "sid": sid,
+                "client_secret": client_secret,
+                "id_server": "",
+                "id_access_token": "",
+            }),
+        );
+        AuthData::new(
+            AuthType::EmailIdentity.as_str(),
+            self.info.session.clone(),
+            data,
+        )
+        .ok()
+    }
+
+    /// Returns the authentication data for the `m.login.dummy` stage
+    fn dummy(&self) -> AuthData {
+        let mut auth = uiaa::Dummy::new();
+        auth.session.clone_from(&self.info.session);
+        AuthData::Dummy(auth)
+    }
+
+    /// Returns whether the next stage of some flow needs no input from the user
+    fn dummy_suffices(&self) -> bool {
+        self.next_stages().contains(&AuthType::Dummy)
+    }
+
+    /// Returns whether some flow needs email validation
+    fn needs_email(&self) -> bool {
+        self.remaining_flows()
+            .iter()
+            .any(|flow| flow.contains(&AuthType::EmailIdentity))
+    }
+}
+
+impl DataStore {
+    /// Registers a new account on the homeserver
+    ///
+    /// The first call should pass `None` as `auth`. If the homeserver requires authentication stages that need input from the user, [`RegistrationError::AdditionalAuthRequired`] is returned, and registration continues by calling this function again with the authentication data built from the returned [`PendingRegistration`]. If `email` is given and the homeserver accepts email validation, a validation email is sent.
+    ///
+    /// On success, the session is persisted and syncing starts, like after [`login`](Self::login).
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, if registration fails, or if further authentication is required.
+    pub async fn register(
+        &self,
+        username: impl AsRef<str> + Send,
+        password: impl AsRef<str> + Send,
+        email: Option<&str>,
+        auth: Option<AuthData>,
+    ) -> Result<(), RegistrationError> {
+        self.ensure_online()?;
+        let client = self
+            .client
+            .read()
+            .await
+            .clone()
+            .ok_or_else(|| eyre::eyre!("No homeserver has been selected"))?;
+
+        let mut auth = auth;
+        let mut email_credentials = None;
+        let response = loop {
+            let mut request = register::v3::Request::new();
+            request.username = Some(username.as_ref().to_owned());
+            request.password = Some(password.as_ref().to_owned());
+            request.initial_device_display_name = Some("rachat".to_owned());
+            request.refresh_token = true;
+            request.auth = auth.take();
+            let sent_dummy = matches!(request.auth, Some(AuthData::Dummy(_)));
+
+            let e = match client.matrix_auth().register(request).await {
+                Ok(response) => break response,
+                Err(e) => e,
+            };
+            let Some(info) = e.as_uiaa_response().cloned() else {
+                return Err(classify_error(e));
+            };
+            let mut pending = PendingRegistration {
+                info,
+                email_credentials: email_credentials.clone(),
+            };
+            if !sent_dummy && pending.dummy_suffices() {
+                auth = Some(pending.dummy());
+                continue;
+            }
+            if let Some(email) = email {
+                if pending.email_credentials.is_none() && pending.needs_email() {
+                    email_credentials = Some(request_email_validation(&client, email).await?);
+                    pending.email_credentials.clone_from(&email_credentials);
+                }
+            }
+            return Err(RegistrationError::AdditionalAuthRequired(pending));
+        };
+
+        let (Some(access_token), Some(device_id)) = (response.access_token, response.device_id)
+        else {
+            return Err(eyre::eyre!("The homeserver did not log in the new account").into());
+        };
+        info!("Registered {}, got device_id {device_id}", response.user_id);
+        client
+            .matrix_auth()
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: response.user_id,
+                    device_id,
+                },
+                tokens: MatrixSessionTokens {
+                    access_token,
+                    refresh_token: response.refresh_token,
+                },
+            })
+            .await
+            .context("Logging into the new account")?;
+        Ok(self.finish_login().await?)
+    }
+}
+
+/// Maps a failed registration request to a registration error
+fn classify_error(e: HttpError) -> RegistrationError {
+    match e.client_api_error_kind() {
+        Some(ErrorKind::UserInUse) => RegistrationError::UsernameTaken,
+        Some(ErrorKind::Forbidden) => RegistrationError::RegistrationDisabled,
+        _ => RegistrationError::Other(eyre::Report::new(e).wrap_err("Registering an account")),
+    }
+}
+
+/// Sends an email to validate the email address of a new account
+async fn request_email_validation(
+    client: &Client,
+    email: &str,
+) -> Result<(OwnedSessionId, OwnedClientSecret)> {
+    let client_secret = ClientSecret::new();
+    let response = client
+        .send(
+            request_registration_token_via_email::v3::Request::new(
+                client_secret.clone(),
+                email.to_owned(),
+                UInt::from(1u32),
+            ),
+            None,
+        )
+        .await
+        .with_context(|| format!("Sending a validation email to {email}"))?;
+    Ok((response.sid, client_secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{
+        api::client::uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo},
+        ClientSecret, OwnedSessionId,
+    };
+
+    use super::PendingRegistration;
+
+    #[test]
+    fn test_pending_registration_stages() -> eyre::Result<()> {
+        let mut info = UiaaInfo::new(
+            vec![
+                AuthFlow::new(vec![AuthType::ReCaptcha, AuthType::EmailIdentity]),
+                AuthFlow::new(vec![AuthType::ReCaptcha, AuthType::Dummy]),
+            ],
+            serde_json::value::to_raw_value(
+                &serde_json::json!({ "m.login.recaptcha": { "public_key": "abc" } }),
+            )?,
+        );
+        info.completed = vec![AuthType::ReCaptcha];
+        info.session = Some("session".to_owned());
+        let pending = PendingRegistration {
+            info,
+            email_credentials: Some((OwnedSessionId::try_from("sid")?, ClientSecret::new())),
+        };
+
+        assert_eq!(
+            pending.next_stages(),
+            vec![AuthType::EmailIdentity, AuthType::Dummy]
+        );
+        assert!(pending.dummy_suffices());
+        assert_eq!(
+            pending.params(&AuthType::ReCaptcha),
+            Some(serde_json::json!({ "public_key": "abc" }))
+        );
+        let auth = pending.email_identity();
+        assert!(matches!(auth, Some(AuthData::EmailIdentity(_))));
+        assert_eq!(auth.as_ref().and_then(AuthData::session), Some("session"));
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+//! Registry of open data stores
+//!
+//! Every profile has its own data store, with its own root key, matrix store and sync loop. The registry makes sure each profile is opened at most once.
+
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+
+use directories_next::ProjectDirs;
+use eyre::{Context, Result};
+use secrecy::Secret;
+use tokio::sync::{OnceCell, RwLock};
+
+use super::{builder::DataStoreBuilder, DataStore};
+
+/// Registry of the data stores of all open profiles
+#[derive(Debug)]
+pub struct DataStoreRegistry {
+    /// Project directories the data stores are opened in
+    project_dirs: ProjectDirs,
+    /// Open data stores, keyed by profile name
+    ///
+    /// The cell of a profile is empty while its data store is being opened, so that the map doesn't have to be locked meanwhile.
+    stores: RwLock<BTreeMap<String, Arc<OnceCell<Arc<DataStore>>>>>,
+}
+
+impl DataStoreRegistry {
+    /// Creates an empty registry
+    #[must_use]
+    pub fn new(project_dirs: ProjectDirs) -> Self {
+        Self {
+            project_dirs,
+            stores: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Opens the data store of a profile, or returns it if it is already open
+    ///
+    /// # Errors
+    /// This function returns an error if the data store fails to open. If the profile keeps its root key in a secret file, the error is a [`PassphraseRequiredError`](crate::crypto::PassphraseRequiredError), and the profile has to be opened with [`open_with_passphrase`](Self::open_with_passphrase).
+    pub async fn open(&self, profile: &str) -> Result<Arc<DataStore>> {
+        self.open_with(profile, None).await
+    }
+
+    /// Opens the data store of a profile that keeps its root key in a secret file, or returns it if it is already open
+    ///
+    /// # Errors
+    /// This function returns an error if the data store fails to open, or if the passphrase is wrong.
+    pub async fn open_with_passphrase(
+        &self,
+        profile: &str,
+        passphrase: Secret<String>,
+    ) -> Result<Arc<DataStore>> {
+        self.open_with(profile, Some(passphrase)).await
+    }
+
+    /// Opens the data store of a profile, passing the passphrase of its secret file if there is one
+    async fn open_with(
+        &self,
+        profile: &str,
+        passphrase: Option<Secret<String>>,
+    ) -> Result<Arc<DataStore>> {
+        let mut builder = DataStoreBuilder::new(&self.project_dirs, profile);
+        if let Some(passphrase) = passphrase {
+            builder = builder.secret_passphrase(passphrase);
+        }
+        self.open_once(profile, false, || async move {
+            builder
+                .build()
+                .await
+                .with_context(|| format!("Creating data store for profile {profile}"))
+        })
+        .await
+    }
+
+    /// Opens the data store of a profile with `open`, or returns it if it is already open
+    ///
+    /// The registry is not locked while `open` runs, so that a slow profile doesn't block the others. Concurrent calls for the same profile wait for the first one. If `must_be_new` is set, this fails if the profile is already open or being opened.
+    async fn open_once<F, Fut>(
+        &self,
+        profile: &str,
+        must_be_new: bool,
+        open: F,
+    ) -> Result<Arc<DataStore>>
+    where
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Arc<DataStore>>> + Send,
+    {
+        let cell = {
+            let mut stores = self.stores.write().await;
+            if let Some(cell) = stores.get(profile) {
+                if must_be_new {
+                    eyre::bail!("The profile {profile} already exists");
+                }
+                Arc::clone(cell)
+            } else {
+                let cell = Arc::new(OnceCell::new());
+                stores.insert(profile.to_owned(), Arc::clone(&cell));
+                cell
+            }
+        };
+        let result = cell.get_or_try_init(open).await.cloned();
+
+        let registered = {
+            let mut stores = self.stores.write().await;
+            let registered = stores
+                .get(profile)
+                .is_some_and(|registered| Arc::ptr_eq(registered, &cell));
+            // A failed open leaves the cell empty, which must not keep the profile from being imported
+            if registered && !cell.initialized() {
+                stores.remove(profile);
+            }
+            registered
+        };
+        match result {
+            Ok(data_store) if registered => Ok(data_store),
+            Ok(data_store) => {
+                data_store.shutdown().await;
+                eyre::bail!("The profile {profile} was closed while it was being opened")
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the data store of a profile, if it is open
+    pub async fn get(&self, profile: &str) -> Option<Arc<DataStore>> {
+        self.stores
+            .read()
+            .await
+            .get(profile)
+            .and_then(|cell| cell.get().cloned())
+    }
+
+    /// Closes the data store of a profile, returning whether it was open
+    ///
+    /// The background tasks are stopped immediately. The data store itself is dropped once the last handle to it is gone.
+    pub async fn close(&self, profile: &str) -> bool {
+        let cell = self.stores.write().await.remove(profile);
+        let Some(data_store) = cell.and_then(|cell| cell.get().cloned()) else {
+            return false;
+        };
+        data_store.shutdown().await;
+        true
+    }
+
+    /// Closes the data stores of all profiles
+    pub async fn close_all(&self) {
+        let stores = std::mem::take(&mut *self.stores.write().await);
+        for data_store in stores.into_values().filter_map(|cell| cell.get().cloned()) {
+            data_store.shutdown().await;
+        }
+    }
+
+    /// Imports a profile from a bundle, and opens its data store
+    ///
+    /// See [`DataStore::import_profile`].
+    ///
+    /// # Errors
+    /// This function returns an error if a profile with that name is already open, or if the profile could not be imported.
+    pub async fn import(
+        &self,
+        bundle: &[u8],
+        passphrase: &Secret<String>,
+        profile: &str,
+        secret_passphrase: Option<Secret<String>>,
+    ) -> Result<Arc<DataStore>> {
+        self.open_once(profile, true, || async move {
+            DataStore::import_profile(
+                &self.project_dirs,
+                bundle,
+                passphrase,
+                profile,
+                secret_passphrase,
+            )
+            .await
+            .with_context(|| format!("Importing profile {profile}"))
+        })
+        .await
+    }
+
+    /// Returns the names of all open profiles
+    pub async fn active_profiles(&self) -> Vec<String> {
+        self.stores
+            .read()
+            .await
+            .iter()
+            .filter(|(_, cell)| cell.initialized())
+            .map(|(profile, _)| profile.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use directories_next::ProjectDirs;
+    use matrix_sdk::{
+        matrix_auth::{MatrixSession, MatrixSessionTokens},
+        ruma::{owned_device_id, owned_user_id},
+        ServerName, SessionMeta,
+    };
+    use tokio::sync::{Notify, OnceCell};
+
+    use super::DataStoreRegistry;
+    use crate::{
+        crypto::secret_store::MemorySecretStore,
+        test_utils::{builder, local_client, TempDir},
+    };
+
+    /// Returns project directories for a registry whose data stores are opened by the test itself
+    fn project_dirs() -> eyre::Result<ProjectDirs> {
+        ProjectDirs::from("rs", "Raccoon Productions", "rachat-test")
+            .ok_or_else(|| eyre::eyre!("no home directory"))
+    }
+
+    #[tokio::test]
+    async fn test_opening_does_not_block_the_registry() -> eyre::Result<()> {
+        let registry = Arc::new(DataStoreRegistry::new(project_dirs()?));
+        let started = Arc::new(Notify::new());
+        let opening = tokio::spawn({
+            let registry = Arc::clone(&registry);
+            let started = Arc::clone(&started);
+            async move {
+                registry
+                    .open_once("slow", false, || async move {
+                        started.notify_one();
+                        std::future::pending().await
+                    })
+                    .await
+            }
+        });
+        started.notified().await;
+
+        let timeout = Duration::from_secs(5);
+        assert!(tokio::time::timeout(timeout, registry.get("slow"))
+            .await?
+            .is_none());
+        assert!(!tokio::time::timeout(timeout, registry.close("other")).await?);
+        assert!(tokio::time::timeout(timeout, registry.active_profiles())
+            .await?
+            .is_empty());
+
+        opening.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_releases_the_data_store() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let client = local_client().await?;
+        let data_store = builder(&dir)
+            .secret_store(Arc::new(MemorySecretStore::default()))
+            .build()
+            .await?;
+        client
+            .matrix_auth()
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: owned_user_id!("@alice:example.com"),
+                    device_id: owned_device_id!("DEVICE"),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: "token".to_owned(),
+                    refresh_token: None,
+                },
+            })
+            .await?;
+        // A logged-in client starts the sync loop and the session listener
+        data_store
+            .attach_client(ServerName::parse("example.com")?, client)
+            .await?;
+        assert!(data_store.session_task.read().await.is_some());
+
+        let registry = DataStoreRegistry::new(project_dirs()?);
+        registry.stores.write().await.insert(
+            "test".to_owned(),
+            Arc::new(OnceCell::new_with(Some(Arc::clone(&data_store)))),
+        );
+        assert!(registry.close("test").await);
+        assert_eq!(Arc::strong_count(&data_store), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Room list and messages
+
+use std::sync::Arc;
+
+use eyre::{Context, Result};
+use futures::{stream, Stream};
+use matrix_sdk::{
+    ruma::{
+        events::{
+            room::message::RoomMessageEventContent, AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+            SyncMessageLikeEvent,
+        },
+        OwnedEventId, OwnedRoomId, RoomId,
+    },
+    Room, RoomState,
+};
+use tracing::warn;
+
+use super::DataStore;
+
+/// Summary of a joined room, as shown in the room list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomSummary {
+    /// ID of the room
+    pub room_id: OwnedRoomId,
+    /// Display name of the room
+    pub display_name: String,
+    /// Body of the latest message, if it is known
+    pub last_message: Option<String>,
+    /// Number of unread notifications
+    pub unread_count: u64,
+}
+
+impl RoomSummary {
+    /// Creates a summary of a room
+    async fn new(room: &Room) -> Self {
+        let display_name = match room.display_name().await {
+            Ok(name) => name.to_string(),
+            Err(e) => {
+                warn!(
+                    "Failed to compute display name of {}: {e:#?}",
+                    room.room_id()
+                );
+                room.room_id().to_string()
+            }
+        };
+        let last_message =
+            room.latest_event()
+                .and_then(|event| match event.event().event.deserialize().ok()? {
+                    AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                        SyncMessageLikeEvent::Original(message),
+                    )) => Some(message.content.body().to_owned()),
+                    _ => None,
+                });
+        Self {
+            room_id: room.room_id().to_owned(),
+            display_name,
+            last_message,
+            unread_count: room.unread_notification_counts().notification_count,
+        }
+    }
+}
+
+impl DataStore {
+    /// Returns the rooms the user has joined
+    ///
+    /// The list is empty if there is no client.
+    pub async fn rooms(&self) -> Vec<RoomSummary> {
+        let Some(client) = self.client.read().await.clone() else {
+            return Vec::new();
+        };
+        let mut rooms = Vec::new();
+        for room in client.joined_rooms() {
+            rooms.push(RoomSummary::new(&room).await);
+        }
+        rooms
+    }
+
+    /// Returns a stream of room lists, yielding a new list whenever a sync changed any room
+    ///
+    /// The current list is yielded first.
+    pub fn watch_rooms(self: Arc<Self>) -> impl Stream<Item = Vec<RoomSummary>> {
+        let mut receiver = self.rooms_changed.subscribe();
+        receiver.mark_changed();
+        stream::unfold((self, receiver), |(data_store, mut receiver)| async move {
+            receiver.changed().await.ok()?;
+            let rooms = data_store.rooms().await;
+            Some((rooms, (data_store, receiver)))
+        })
+    }
+
+    /// Sends a plain text message to a joined room, returning the ID of the sent event
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in or has not joined the room, or if sending the message fails.
+    pub async fn send_text(&self, room_id: &RoomId, body: &str) -> Result<OwnedEventId> {
+        self.send_message(room_id, RoomMessageEventContent::text_plain(body))
+            .await
+    }
+
+    /// Sends a markdown formatted message to a joined room, returning the ID of the sent event
+    ///
+    /// # Errors
+    /// This function returns an error if offline mode is enabled, the user is not logged in or has not joined the room, or if sending the message fails.
+    pub async fn send_markdown(&self, room_id: &RoomId, body: &str) -> Result<OwnedEventId> {
+        self.send_message(room_id, RoomMessageEventContent::text_markdown(body))
+            .await
+    }
+
+    /// Sends a message to a joined room
+    async fn send_message(
+        &self,
+        room_id: &RoomId,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId> {
+        self.ensure_online()?;
+        let client = self.logged_in_client().await?;
+        let room = client
+            .get_room(room_id)
+            .filter(|room| room.state() == RoomState::Joined)
+            .ok_or_else(|| eyre::eyre!("Room {room_id} has not been joined"))?;
+        let response = room
+            .send(content)
+            .await
+            .with_context(|| format!("Sending a message to {room_id}"))?;
+        Ok(response.event_id)
+    }
+}
@@ -0,0 +1,439 @@
+//! Moving profiles between machines
+//!
+//! A profile is exported into a single [bundle](crate::crypto::bundle), protected by a passphrase. The plaintext of the bundle is a CBOR map of:
+//!
+//! - `root_key`: the 32 byte root key of the profile
+//! - `config`: the [`ProfileConfig`] of the profile
+//! - `session`: the contents of the `auth/login` mutable file, if the user is logged in
+//! - `store_passphrase`: the passphrase of the matrix store, if the root key has been rotated since the store was created
+//! - `crypto_store`: a snapshot of the encryption database of the matrix store, so that the imported session keeps its device keys
+//!
+//! The room state and the media cache are not exported, they are fetched again by the first sync. The bundle is entirely in memory, and every copy of the plaintext is zeroized once it is no longer needed.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use directories_next::ProjectDirs;
+use eyre::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use secrecy::{ExposeSecret, Secret, Zeroize};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{error, info};
+
+use super::{
+    builder::DataStoreBuilder, maintenance::CRYPTO_DATABASE, DataStore, ProfileConfig,
+    MATRIX_STORE, MATRIX_STORE_PASSPHRASE, PROFILE_CONFIG,
+};
+use crate::crypto::{bundle, KDFSecretKey, SecretStore};
+
+/// Associated data of profile bundles
+const BUNDLE_AAD: &[u8] = b"rs.chir.rachat.profile-bundle";
+
+/// How long to wait for the matrix client to release the encryption database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Plaintext of a profile bundle
+#[derive(Serialize, Deserialize)]
+struct ProfileBundle {
+    /// Root key of the profile
+    root_key: [u8; 32],
+    /// Configuration of the profile
+    config: ProfileConfig,
+    /// Serialized login session
+    session: Option<Vec<u8>>,
+    /// Passphrase of the matrix store, if it is not derived from the root key
+    store_passphrase: Option<Vec<u8>>,
+    /// Snapshot of the encryption database of the matrix store
+    crypto_store: Option<Vec<u8>>,
+}
+
+impl Drop for ProfileBundle {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.session.zeroize();
+        self.store_passphrase.zeroize();
+        self.crypto_store.zeroize();
+    }
+}
+
+impl DataStore {
+    /// Exports the profile into a bundle encrypted with `passphrase`
+    ///
+    /// The bundle can be imported on another machine with [`import_profile`](Self::import_profile). It holds the root key and the login session, so anyone who has it and knows the passphrase can access the account.
+    ///
+    /// # Errors
+    /// This function returns an error if no homeserver has been selected, or if the profile could not be read or encrypted.
+    pub async fn export_profile(&self, passphrase: &Secret<String>) -> Result<Vec<u8>> {
+        let config = self
+            .config
+            .read()
+            .await
+            .clone()
+            .ok_or_else(|| eyre::eyre!("No homeserver has been selected"))?;
+        let session = self
+            .root_key
+            .open_mutable_file(&self.data_dir, "auth/login")
+            .read()
+            .await
+            .context("Reading auth/login")?;
+        let store_passphrase = self
+            .open_mutable_file(MATRIX_STORE_PASSPHRASE)
+            .read()
+            .await
+            .context("Reading the stored matrix store passphrase")?;
+        let crypto_store = snapshot_database(
+            &config
+                .matrix_store_path(&self.data_dir)
+                .join(CRYPTO_DATABASE),
+            &self
+                .cache_dir
+                .join(format!("export-{:016x}.sqlite3", rand::random::<u64>())),
+        )
+        .await?;
+
+        let contents = ProfileBundle {
+            root_key: *self.root_key.expose_bytes(),
+            config,
+            session,
+            store_passphrase,
+            crypto_store,
+        };
+        let mut plaintext = Vec::new();
+        if let Err(e) =
+            ciborium::ser::into_writer(&contents, &mut plaintext).context("Serializing the profile")
+        {
+            plaintext.zeroize();
+            return Err(e);
+        }
+        let passphrase = Secret::new(passphrase.expose_secret().clone());
+        // Deriving the key is deliberately slow, so it must not hold up an async worker thread
+        let result = tokio::task::spawn_blocking(move || {
+            let bundle = bundle::seal(&passphrase, &plaintext, BUNDLE_AAD);
+            plaintext.zeroize();
+            bundle
+        })
+        .await
+        .context("Encrypting the profile")?;
+        if result.is_ok() {
+            info!("Exported profile {}", self.profile);
+        }
+        result
+    }
+
+    /// Imports a profile from a bundle created by [`export_profile`](Self::export_profile), and opens its data store
+    ///
+    /// The root key is stored in the secret store selected by the imported configuration, under the new profile name. `secret_passphrase` is needed if that is a secret file. The matrix store is always placed in the default location, as the location on the exporting machine may not exist here.
+    ///
+    /// If the import fails, the root key and the files written so far are removed again, so that it can be retried.
+    ///
+    /// # Errors
+    /// This function returns an error if the passphrase is wrong, the bundle is malformed, a profile with that name already exists, or if the profile could not be written or opened.
+    pub async fn import_profile(
+        project_dirs: &ProjectDirs,
+        bundle: &[u8],
+        passphrase: &Secret<String>,
+        profile: &str,
+        secret_passphrase: Option<Secret<String>>,
+    ) -> Result<Arc<Self>> {
+        let mut builder = DataStoreBuilder::new(project_dirs, profile);
+        if let Some(secret_passphrase) = secret_passphrase {
+            builder = builder.secret_passphrase(secret_passphrase);
+        }
+        Self::import_with(builder, bundle, passphrase).await
+    }
+
+    /// Imports a profile from a bundle into the directories and secret store of `builder`, and opens its data store with it
+    async fn import_with(
+        mut builder: DataStoreBuilder,
+        bundle: &[u8],
+        passphrase: &Secret<String>,
+    ) -> Result<Arc<Self>> {
+        let passphrase = Secret::new(passphrase.expose_secret().clone());
+        let bundle = bundle.to_vec();
+        // Deriving the key is deliberately slow, so it must not hold up an async worker thread
+        let mut plaintext =
+            tokio::task::spawn_blocking(move || bundle::open(&passphrase, &bundle, BUNDLE_AAD))
+                .await
+                .context("Decrypting the profile")??;
+        let contents: Result<ProfileBundle> =
+            ciborium::de::from_reader(plaintext.as_slice()).context("Parsing the profile bundle");
+        plaintext.zeroize();
+        let mut contents = contents?;
+
+        let mut config = contents.config.clone();
+        config.store_path = None;
+        let secret_store = builder.resolve_secret_store(Some(&config))?;
+        let (profile, config_dir, data_dir) = builder.profile_dirs();
+        let (profile, config_dir, data_dir) = (
+            profile.to_owned(),
+            config_dir.to_owned(),
+            data_dir.to_owned(),
+        );
+        let config_path = config_dir.join(PROFILE_CONFIG);
+        if fs::try_exists(&config_path)
+            .await
+            .with_context(|| format!("Checking whether {} exists", config_path.display()))?
+            || KDFSecretKey::exists_in_store(secret_store.as_ref(), &profile).await?
+        {
+            eyre::bail!("The profile {profile} already exists");
+        }
+        let mut created_dirs = Vec::new();
+        for dir in [&config_dir, &data_dir] {
+            if !fs::try_exists(dir)
+                .await
+                .with_context(|| format!("Checking whether {} exists", dir.display()))?
+            {
+                created_dirs.push(dir.clone());
+            }
+        }
+
+        let root_key = KDFSecretKey::from_bytes(&mut contents.root_key);
+        let result = async {
+            // The root key goes first, so that a failed import never leaves files behind that can't be decrypted
+            root_key
+                .store_in(secret_store.as_ref(), &profile)
+                .await
+                .context("Storing the root key")?;
+            write_profile(&root_key, &contents, &config, &config_dir, &data_dir).await?;
+            builder.build().await
+        }
+        .await;
+        drop(contents);
+
+        match result {
+            Ok(data_store) => {
+                info!("Imported profile {profile}");
+                Ok(data_store)
+            }
+            Err(e) => {
+                roll_back_import(
+                    secret_store.as_ref(),
+                    &profile,
+                    &root_key,
+                    &config_dir,
+                    &data_dir,
+                    &created_dirs,
+                )
+                .await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Writes the configuration and the files of an imported profile
+async fn write_profile(
+    root_key: &KDFSecretKey,
+    contents: &ProfileBundle,
+    config: &ProfileConfig,
+    config_dir: &Path,
+    data_dir: &Path,
+) -> Result<()> {
+    fs::create_dir_all(config_dir)
+        .await
+        .context("Creating config directory")?;
+    if let Some(session) = &contents.session {
+        root_key
+            .open_mutable_file(data_dir, "auth/login")
+            .write(session)
+            .await
+            .context("Writing auth/login")?;
+    }
+    if let Some(store_passphrase) = &contents.store_passphrase {
+        root_key
+            .open_mutable_file(data_dir, MATRIX_STORE_PASSPHRASE)
+            .write(store_passphrase)
+            .await
+            .context("Writing the matrix store passphrase")?;
+    }
+    if let Some(crypto_store) = &contents.crypto_store {
+        let store_path = config.matrix_store_path(data_dir);
+        fs::create_dir_all(&store_path)
+            .await
+            .with_context(|| format!("Creating {}", store_path.display()))?;
+        fs::write(store_path.join(CRYPTO_DATABASE), crypto_store)
+            .await
+            .context("Writing the encryption database")?;
+    }
+    config.save(config_dir).await
+}
+
+/// Removes the root key and the files of a failed import
+///
+/// Directories in `created_dirs` did not exist before the import and are removed entirely, otherwise only the files the import writes are removed. Failures are logged, as the import has failed already.
+async fn roll_back_import(
+    secret_store: &dyn SecretStore,
+    profile: &str,
+    root_key: &KDFSecretKey,
+    config_dir: &Path,
+    data_dir: &Path,
+    created_dirs: &[PathBuf],
+) {
+    if let Err(e) = KDFSecretKey::delete_from_store(secret_store, profile).await {
+        error!("Failed to remove the root key of the failed import of {profile}: {e:#}");
+    }
+    for file in ["auth/login", MATRIX_STORE_PASSPHRASE] {
+        if let Err(e) = root_key.open_mutable_file(data_dir, file).delete().await {
+            error!("Failed to remove {file} of the failed import of {profile}: {e:#}");
+        }
+    }
+    for path in [
+        config_dir.join(PROFILE_CONFIG),
+        data_dir.join(MATRIX_STORE).join(CRYPTO_DATABASE),
+    ] {
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!("Failed to remove {}: {e:#}", path.display());
+            }
+            _ => {}
+        }
+    }
+    for dir in created_dirs {
+        if let Err(e) = fs::remove_dir_all(dir).await {
+            error!("Failed to remove {}: {e:#}", dir.display());
+        }
+    }
+}
+
+/// Returns a consistent copy of a sqlite database, or `None` if it does not exist
+///
+/// The copy is written to `snapshot_path` and deleted again after reading it.
+async fn snapshot_database(path: &Path, snapshot_path: &Path) -> Result<Option<Vec<u8>>> {
+    let path = path.to_owned();
+    let snapshot_path = snapshot_path.to_owned();
+    tokio::task::spawn_blocking(move || -> Result<Option<Vec<u8>>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        if let Some(parent) = snapshot_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Creating {}", parent.display()))?;
+        }
+        let connection = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .context("Setting the busy timeout")?;
+        let snapshot_name = snapshot_path
+            .to_str()
+            .ok_or_else(|| eyre::eyre!("{} is not valid UTF-8", snapshot_path.display()))?;
+        let data = connection
+            .execute("VACUUM INTO ?", [snapshot_name])
+            .with_context(|| format!("Copying {}", path.display()))
+            .and_then(|_| {
+                std::fs::read(&snapshot_path)
+                    .with_context(|| format!("Reading {}", snapshot_path.display()))
+            });
+        // The snapshot holds the keys of the session, so it is removed even if copying failed halfway
+        let removed = match std::fs::remove_file(&snapshot_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Deleting {}", snapshot_path.display()))
+            }
+            _ => Ok(()),
+        };
+        let data = data?;
+        removed?;
+        Ok(Some(data))
+    })
+    .await
+    .context("Copying the encryption database")?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use rusqlite::Connection;
+    use secrecy::Secret;
+
+    use super::snapshot_database;
+    use crate::{
+        crypto::{secret_store::MemorySecretStore, KDFSecretKey},
+        data_store::DataStore,
+        test_utils::{builder, local_client, TempDir},
+    };
+
+    #[tokio::test]
+    async fn test_failed_import_can_be_retried() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir_all(dir.join("old/config"))?;
+        std::fs::write(
+            dir.join("old/config/config.json"),
+            r#"{"server_name":"example.com"}"#,
+        )?;
+        let exported = builder(&dir.join("old"))
+            .secret_store(Arc::new(MemorySecretStore::default()))
+            .client(local_client().await?)
+            .build()
+            .await?;
+        exported
+            .set_idle_logout(Some(Duration::from_secs(30)))
+            .await?;
+        let passphrase = Secret::new("correct horse battery staple".to_owned());
+        let bundle = exported.export_profile(&passphrase).await?;
+
+        // Opening the imported profile fails, as its cache directory can't be created
+        let new_dir = dir.join("new");
+        std::fs::create_dir_all(&new_dir)?;
+        std::fs::write(new_dir.join("cache"), b"")?;
+        let new_store = Arc::new(MemorySecretStore::default());
+        let client = local_client().await?;
+        let new_builder = || {
+            builder(&new_dir)
+                .secret_store(Arc::clone(&new_store) as _)
+                .client(client.clone())
+        };
+        let result = DataStore::import_with(new_builder(), &bundle, &passphrase).await;
+        assert!(result.is_err());
+        assert!(!KDFSecretKey::exists_in_store(new_store.as_ref(), "test").await?);
+        assert!(!new_dir.join("config").exists());
+        assert!(!new_dir.join("data").exists());
+
+        std::fs::remove_file(new_dir.join("cache"))?;
+        let imported = DataStore::import_with(new_builder(), &bundle, &passphrase).await?;
+        assert_eq!(
+            imported.root_key.expose_bytes(),
+            exported.root_key.expose_bytes()
+        );
+        assert_eq!(imported.idle_logout().await, Some(Duration::from_secs(30)));
+
+        // A profile that has been imported can't be imported again
+        assert!(DataStore::import_with(new_builder(), &bundle, &passphrase)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_database() -> eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.join("db.sqlite3");
+        let connection = Connection::open(&path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = wal; CREATE TABLE kv (key TEXT); INSERT INTO kv VALUES ('a');",
+        )?;
+
+        let snapshot_path = dir.join("snapshot.sqlite3");
+        let snapshot = snapshot_database(&path, &snapshot_path)
+            .await?
+            .ok_or_else(|| eyre::eyre!("missing snapshot"))?;
+        assert!(!snapshot_path.exists());
+
+        let copy_path = dir.join("copy.sqlite3");
+        std::fs::write(&copy_path, snapshot)?;
+        let key: String =
+            Connection::open(&copy_path)?.query_row("SELECT key FROM kv", [], |row| row.get(0))?;
+        assert_eq!(key, "a");
+
+        assert!(
+            snapshot_database(&dir.join("missing.sqlite3"), &snapshot_path)
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+}
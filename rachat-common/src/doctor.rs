@@ -0,0 +1,145 @@
+//! Diagnostics of the runtime environment
+//!
+//! These checks don't need a working [`Rachat`](crate::Rachat) instance, so they can explain why creating one fails.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use directories_next::ProjectDirs;
+use eyre::{Context, Result};
+use tokio::fs;
+
+use crate::{
+    config::Config,
+    crypto::{KDFSecretKey, Keyring, SecretBackend},
+    data_store::{DataStore, ProfileConfig},
+};
+
+/// Severity of a diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The check passed
+    Ok,
+    /// Rachat works, but something may not behave as expected
+    Warning,
+    /// Rachat can't work like this
+    Error,
+}
+
+/// Result of a single check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Severity of the result
+    pub severity: Severity,
+    /// Human readable description of the result
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic from the result of a check
+    fn from_result(what: &str, result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Self {
+                severity: Severity::Ok,
+                message: format!("{what}: ok"),
+            },
+            Err(e) => Self {
+                severity: Severity::Error,
+                message: format!("{what}: {e:#}"),
+            },
+        }
+    }
+
+    /// Reports a failure as a warning instead of an error
+    fn downgrade(mut self) -> Self {
+        self.severity = self.severity.min(Severity::Warning);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Ok => "OK",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+        };
+        write!(f, "[{severity}] {}", self.message)
+    }
+}
+
+/// Runs all checks of the runtime environment
+pub async fn run(project_dirs: Option<&ProjectDirs>) -> Vec<Diagnostic> {
+    let Some(project_dirs) = project_dirs else {
+        return vec![Diagnostic {
+            severity: Severity::Error,
+            message: "Project directories: no home directory found".to_owned(),
+        }];
+    };
+    let mut diagnostics = vec![Diagnostic::from_result(
+        "Configuration directory",
+        check_writeable(project_dirs.config_dir()).await,
+    )];
+    diagnostics.extend(check_secret_store(project_dirs).await);
+    diagnostics.push(Diagnostic::from_result(
+        "Configuration file",
+        Config::new(project_dirs)
+            .default_profile()
+            .await
+            .map(|_| ()),
+    ));
+    diagnostics
+}
+
+/// Checks the secret store of the profile that would be opened
+///
+/// A secret file can't be opened without its passphrase, so for profiles that use one only its directory is checked. The keyring is still checked for them, as new profiles use it, but a failure is only a warning.
+async fn check_secret_store(project_dirs: &ProjectDirs) -> Vec<Diagnostic> {
+    let (config_dir, backend) = match chosen_secret_backend(project_dirs).await {
+        Ok(backend) => backend,
+        Err(e) => return vec![Diagnostic::from_result("Secret store", Err(e))],
+    };
+    match backend {
+        SecretBackend::Keyring => vec![Diagnostic::from_result(
+            "Keyring",
+            KDFSecretKey::check_secret_store(&Keyring).await,
+        )],
+        SecretBackend::File => vec![
+            Diagnostic::from_result("Secret file directory", check_writeable(&config_dir).await),
+            Diagnostic::from_result(
+                "Keyring (used by new profiles)",
+                KDFSecretKey::check_secret_store(&Keyring).await,
+            )
+            .downgrade(),
+        ],
+    }
+}
+
+/// Returns the configuration directory of the chosen profile, and where it keeps its root key
+async fn chosen_secret_backend(project_dirs: &ProjectDirs) -> Result<(PathBuf, SecretBackend)> {
+    let config = Config::new(project_dirs);
+    let profile = config.chosen_profile().await?;
+    let (config_dir, _, _) = DataStore::profile_dirs(project_dirs, &profile);
+    let backend = ProfileConfig::load(&config_dir)
+        .await?
+        .map(|config| config.secret_backend)
+        .unwrap_or_default();
+    Ok((config_dir, backend))
+}
+
+/// Checks that a directory can be written to
+async fn check_writeable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Creating {}", dir.display()))?;
+    let probe = dir.join(format!(".doctor-{:016x}", rand::random::<u64>()));
+    fs::write(&probe, b"")
+        .await
+        .with_context(|| format!("Writing to {}", dir.display()))?;
+    fs::remove_file(&probe)
+        .await
+        .with_context(|| format!("Removing {}", probe.display()))?;
+    Ok(())
+}
@@ -7,38 +7,59 @@ use directories_next::ProjectDirs;
 use eyre::{Context, OptionExt, Result};
 use std::sync::Arc;
 use tokio::fs;
+use tracing::error;
 
 pub mod config;
 pub mod crypto;
 pub mod data_store;
+pub mod doctor;
+#[cfg(test)]
+mod test_utils;
 pub(crate) mod utils;
 
 /// Root application state
 #[derive(Debug)]
 pub struct Rachat {
-    /// Data store
+    /// Data stores of all open profiles
+    data_stores: data_store::registry::DataStoreRegistry,
+    /// Data store of the chosen profile
     data_store: Arc<data_store::DataStore>,
     /// Global configuration
     config: Arc<Config>,
 }
 
 impl Rachat {
+    /// Checks the runtime environment, returning a report of every check
+    pub async fn doctor() -> Vec<doctor::Diagnostic> {
+        doctor::run(Self::project_dirs().as_ref()).await
+    }
+
+    /// Returns the project directories of rachat
+    fn project_dirs() -> Option<ProjectDirs> {
+        ProjectDirs::from("rs", "Raccoon Productions", "rachat")
+    }
+
     /// Attempts to create a new Rachat instance
     ///
     /// # Errors
     /// This function returns an error if the project directories can’t be found, the configuration file can’t be read, or the data store fails to open.
     pub async fn new() -> Result<Arc<Self>> {
-        let project_dirs = ProjectDirs::from("rs", "Raccoon Productions", "rachat")
-            .ok_or_eyre("Missing project directories")?;
+        let project_dirs = Self::project_dirs().ok_or_eyre("Missing project directories")?;
         fs::create_dir_all(project_dirs.config_dir())
             .await
             .context("Creating project directories")?;
         let config = Config::new(&project_dirs);
-        let profile = config.chosen_profile().await?;
-        let data_store = data_store::DataStore::new(&project_dirs, &profile)
+        let profile = config
+            .chosen_profile()
             .await
-            .with_context(|| format!("Creating data store for profile {profile}",))?;
-        Ok(Arc::new(Self { data_store, config }))
+            .context("Loading the global configuration")?;
+        let data_stores = data_store::registry::DataStoreRegistry::new(project_dirs);
+        let data_store = data_stores.open(&profile).await?;
+        Ok(Arc::new(Self {
+            data_stores,
+            data_store,
+            config,
+        }))
     }
 
     /// Returns a handle to the data store
@@ -46,4 +67,26 @@ impl Rachat {
     pub fn data_store(&self) -> Arc<data_store::DataStore> {
         Arc::clone(&self.data_store)
     }
+
+    /// Returns the registry of data stores, for opening further profiles
+    #[must_use]
+    pub const fn data_stores(&self) -> &data_store::registry::DataStoreRegistry {
+        &self.data_stores
+    }
+
+    /// Shuts down background work, persisting state that has not been written yet
+    ///
+    /// This should be called before the application exits.
+    pub async fn shutdown(&self) {
+        if let Err(e) = self.config.flush_window_geometry().await {
+            error!("Failed to save the window geometry: {e:#?}");
+        }
+        self.data_stores.close_all().await;
+    }
+
+    /// Returns the global configuration
+    #[must_use]
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config)
+    }
 }
@@ -0,0 +1,64 @@
+//! Fixtures shared by the tests
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use matrix_sdk::Client;
+
+use crate::data_store::builder::DataStoreBuilder;
+
+/// Temporary directory, removed again when dropped
+///
+/// The directory is removed even if the test fails halfway.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a fresh temporary directory
+    pub fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("rachat-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Tests may remove the directory themselves
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns a builder for a data store with its directories below `dir`
+pub fn builder(dir: &Path) -> DataStoreBuilder {
+    DataStoreBuilder::with_dirs(
+        "test",
+        dir.join("config"),
+        dir.join("data"),
+        dir.join("cache"),
+    )
+}
+
+/// Returns a client for a homeserver on localhost, which can be built without network access
+pub async fn local_client() -> eyre::Result<Client> {
+    Ok(Client::builder()
+        .homeserver_url("http://localhost:8008")
+        .build()
+        .await?)
+}
@@ -1,6 +1,6 @@
 //! miscellaneous utilities
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[cfg(unix)]
 /// Converts a path to a stable bytewise representation
@@ -27,6 +27,31 @@ pub fn path_to_bytes(path: impl AsRef<Path>) -> Vec<u8> {
     out_buf
 }
 
+#[cfg(unix)]
+/// Converts the bytewise representation of a path back into a path
+#[allow(clippy::unnecessary_wraps)] // Decoding can fail on windows
+pub fn path_from_bytes(bytes: impl AsRef<[u8]>) -> Option<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    Some(PathBuf::from(OsStr::from_bytes(bytes.as_ref())))
+}
+
+#[cfg(windows)]
+/// Converts the bytewise representation of a path back into a path
+pub fn path_from_bytes(bytes: impl AsRef<[u8]>) -> Option<PathBuf> {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+
+    let bytes = bytes.as_ref();
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let wide = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<u16>>();
+    Some(PathBuf::from(OsString::from_wide(&wide)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -39,6 +64,12 @@ mod tests {
         assert_eq!(bytes, b"/foo/bar");
     }
 
+    #[test]
+    fn test_path_from_bytes() {
+        let path = Path::new("foo/bär");
+        assert_eq!(path_from_bytes(path_to_bytes(path)).as_deref(), Some(path));
+    }
+
     #[cfg(windows)]
     #[test]
     fn test_path_to_bytes() {
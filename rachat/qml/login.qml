@@ -6,6 +6,7 @@ import rs.chir.rachat 1.0
 Item {
     LoginWindow {
         id: loginWindow
+        onOpenSsoUrl: url => Qt.openUrlExternally(url)
     }
     Label {
         id: loginTitle
@@ -65,4 +66,12 @@ Item {
         padding: 8
         onClicked: loginWindow.login(usernameTextField.text, passwordTextField.text)
     }
+    Button {
+        id: ssoLoginButton
+        text: qsTr("Login with SSO")
+        anchors.top: passwordLabel.bottom
+        anchors.left: loginButton.right
+        padding: 8
+        onClicked: loginWindow.loginSso()
+    }
 }
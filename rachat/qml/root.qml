@@ -7,18 +7,99 @@ import QtQuick.Window 2.12
 import rs.chir.rachat 1.0
 
 ApplicationWindow {
+    id: window
+
+    // The geometry is only saved once it has been restored, so the defaults don't overwrite it
+    property bool geometryRestored: false
+    // Size and position while not maximized, which is what gets restored
+    property rect normalGeometry
+
+    function saveGeometry() {
+        if (!geometryRestored)
+            return;
+        if (visibility === Window.Windowed)
+            normalGeometry = Qt.rect(x, y, width, height);
+        rootWindow.saveGeometry(normalGeometry.width, normalGeometry.height, normalGeometry.x, normalGeometry.y, visibility === Window.Maximized);
+    }
+
     height: 480
     title: qsTr("%1 — Rachat").arg(rootWindow.titleString)
     visible: true
     width: 640
 
+    onHeightChanged: saveGeometry()
+    onVisibilityChanged: saveGeometry()
+    onWidthChanged: saveGeometry()
+    onXChanged: saveGeometry()
+    onYChanged: saveGeometry()
+
     RootWindow {
         id: rootWindow
+        onGeometryRestored: (width, height, x, y, hasPosition, maximized) => {
+            window.width = width;
+            window.height = height;
+            if (hasPosition) {
+                window.x = x;
+                window.y = y;
+            }
+            window.normalGeometry = Qt.rect(window.x, window.y, width, height);
+            if (maximized)
+                window.showMaximized();
+            window.geometryRestored = true;
+        }
         onNextUrlChanged: loader.source = rootWindow.nextUrl
     }
 
+    header: Pane {
+        visible: rootWindow.errorString !== ""
+
+        Row {
+            spacing: 8
+
+            Label {
+                anchors.verticalCenter: parent.verticalCenter
+                color: "red"
+                text: rootWindow.errorString
+            }
+            Button {
+                text: qsTr("Dismiss")
+
+                onClicked: rootWindow.errorString = ""
+            }
+        }
+    }
+
+    // Keys consumed by the focused control don't reach the loader, so count the edits and presses they cause instead
+    Connections {
+        function onPressedChanged() {
+            rootWindow.markActive();
+        }
+        function onTextChanged() {
+            rootWindow.markActive();
+        }
+
+        ignoreUnknownSignals: true
+        target: window.activeFocusItem
+    }
+
     Loader {
         id: loader
         anchors.fill: parent
+        focus: true
+
+        Keys.onPressed: event => {
+            rootWindow.markActive();
+            event.accepted = false;
+        }
+
+        // Report user input for the idle logout, without taking events away from the page
+        HoverHandler {
+            onPointChanged: rootWindow.markActive()
+        }
+        TapHandler {
+            acceptedButtons: Qt.AllButtons
+            gesturePolicy: TapHandler.DragThreshold
+            onPressedChanged: rootWindow.markActive()
+        }
     }
 }
@@ -19,6 +19,7 @@ pub mod qobject {
         #[qml_element]
         #[qproperty(QString, title_string)]
         #[qproperty(QUrl, next_url)]
+        #[qproperty(QString, error_string)]
         type RootWindow = super::RootWindowRust;
 
         #[qobject]
@@ -40,6 +41,27 @@ pub mod qobject {
     impl cxx_qt::Constructor<()> for LoginWindow {}
 
     unsafe extern "RustQt" {
+        #[qinvokable]
+        fn mark_active(self: &RootWindow);
+        #[qinvokable]
+        fn save_geometry(
+            self: &RootWindow,
+            width: i32,
+            height: i32,
+            x: i32,
+            y: i32,
+            maximized: bool,
+        );
+        #[qsignal]
+        fn geometry_restored(
+            self: Pin<&mut RootWindow>,
+            width: i32,
+            height: i32,
+            x: i32,
+            y: i32,
+            has_position: bool,
+            maximized: bool,
+        );
         #[qinvokable]
         fn select_homeserver(self: &SelectHomeserver, homeserver: QString);
         #[qinvokable]
@@ -48,30 +70,38 @@ pub mod qobject {
         fn deselect_homeserver(self: &LoginWindow);
         #[qinvokable]
         fn login(self: &LoginWindow, username: QString, password: QString);
+        #[qinvokable]
+        fn login_sso(self: &LoginWindow);
+        #[qsignal]
+        fn open_sso_url(self: Pin<&mut LoginWindow>, url: QUrl);
     }
 }
 
 use core::pin::Pin;
 use cxx_qt::{Initialize, Threading};
 use cxx_qt_lib::{QString, QUrl};
+use rachat_common::config::config_file::WindowGeometry;
 
 /// The Rust struct for the QObject
 #[derive(Default)]
 pub struct RootWindowRust {
     title_string: QString,
     next_url: QUrl,
+    error_string: QString,
 }
 
 impl Initialize for qobject::RootWindow {
     fn initialize(self: Pin<&mut Self>) {
         let thread = self.qt_thread();
         APP_STATE.set_root_window(thread);
+        APP_STATE.spawn_restore_window_geometry();
+        APP_STATE.spawn_idle_logout();
         APP_STATE.spawn(|| async move {
             let has_no_client = rachat()
                 .data_store()
                 .with_client(|client| async move {
                     if !client.logged_in() {
-                        APP_STATE.navigate(RachatPages::Login)?;
+                        APP_STATE.navigate_page(RachatPages::Login)?;
                     } else {
                         todo!();
                     }
@@ -80,13 +110,33 @@ impl Initialize for qobject::RootWindow {
                 .await?
                 .is_none();
             if has_no_client {
-                APP_STATE.navigate(RachatPages::SelectHomeserver)?;
+                APP_STATE.navigate_page(RachatPages::SelectHomeserver)?;
             }
             Ok(())
         });
     }
 }
 
+impl qobject::RootWindow {
+    /// Called by QML on user input, to restart the idle logout timer
+    pub fn mark_active(&self) {
+        APP_STATE.mark_active();
+    }
+
+    /// Called by QML when the window is resized, moved or maximized
+    ///
+    /// The size and position are those of the window while it is not maximized.
+    pub fn save_geometry(&self, width: i32, height: i32, x: i32, y: i32, maximized: bool) {
+        APP_STATE.save_window_geometry(WindowGeometry {
+            width,
+            height,
+            x: Some(x),
+            y: Some(y),
+            maximized,
+        });
+    }
+}
+
 impl Drop for RootWindowRust {
     fn drop(&mut self) {
         crate::APP_STATE.remove_root_window();
@@ -4,7 +4,11 @@ use cxx_qt::{Initialize, Threading};
 use tracing::error;
 
 pub use crate::cxxqt_object::qobject::LoginWindow;
-use crate::{cxxqt_object::qobject::QString, pages::RachatPages, APP_STATE};
+use crate::{
+    cxxqt_object::qobject::{QString, QUrl},
+    pages::RachatPages,
+    APP_STATE,
+};
 
 #[derive(Default)]
 pub struct LoginWindowRust {
@@ -30,11 +34,11 @@ impl Initialize for LoginWindow {
                 }
                 Ok(Some(None)) | Ok(None) => {
                     error!("Login window shown despite no homeserver selected!");
-                    APP_STATE.navigate(RachatPages::SelectHomeserver)?;
+                    APP_STATE.navigate_page(RachatPages::SelectHomeserver)?;
                 }
                 Err(e) => {
                     error!("Error getting homeserver: {}", e);
-                    APP_STATE.navigate(RachatPages::SelectHomeserver)?;
+                    APP_STATE.navigate_page(RachatPages::SelectHomeserver)?;
                 }
             }
             Ok::<(), eyre::Error>(())
@@ -44,16 +48,16 @@ impl Initialize for LoginWindow {
 
 impl LoginWindow {
     pub fn deselect_homeserver(&self) {
-        APP_STATE.spawn(|| async move {
+        APP_STATE.spawn_reporting(|| async move {
             let data_store = crate::rachat().data_store();
             data_store.reset_homeserver().await?;
-            APP_STATE.navigate(RachatPages::SelectHomeserver)?;
+            APP_STATE.navigate_page(RachatPages::SelectHomeserver)?;
             Ok::<(), eyre::Error>(())
         });
     }
 
     pub fn login(&self, username: QString, password: QString) {
-        APP_STATE.spawn(move || async move {
+        APP_STATE.spawn_reporting(move || async move {
             crate::rachat()
                 .data_store()
                 .login(username.to_string(), password.to_string())
@@ -61,4 +65,22 @@ impl LoginWindow {
             Ok(())
         })
     }
+
+    pub fn login_sso(&self) {
+        let thread = self.qt_thread();
+        APP_STATE.spawn_reporting(move || async move {
+            crate::rachat()
+                .data_store()
+                .login_sso(move |url| {
+                    let result = thread.queue(move |window| {
+                        window.open_sso_url(QUrl::from(url.as_str()));
+                    });
+                    if let Err(e) = result {
+                        error!("Failed to open SSO login URL: {e:?}");
+                    }
+                })
+                .await?;
+            Ok(())
+        })
+    }
 }
@@ -3,16 +3,23 @@ pub mod login_window;
 pub mod pages;
 pub mod select_homeserver;
 
-use std::{fmt::Debug, future::Future, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::{pin, Pin},
+    sync::Arc,
+    time::Duration,
+};
 
 use cxx_qt::CxxQtThread;
-use cxx_qt_lib::{QGuiApplication, QQmlApplicationEngine, QString, QUrl};
+use cxx_qt_lib::{QGuiApplication, QQmlApplicationEngine, QString};
 use cxxqt_object::qobject::RootWindow;
 use eyre::Result;
+use futures::StreamExt;
 use once_cell::sync::{Lazy, OnceCell};
 use pages::RachatPages;
 use parking_lot::Mutex;
-use rachat_common::Rachat;
+use rachat_common::{config::config_file::WindowGeometry, Rachat};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, info_span, warn};
 
@@ -65,17 +72,78 @@ impl AppState {
         Ok(())
     }
 
-    /// Navigate to the given URL asynchronously.
-    pub fn navigate<S>(&self, url: S) -> Result<()>
-    where
-        S: Into<QUrl> + AsRef<str> + Send + 'static,
-    {
-        let span = info_span!("navigate", url = url.as_ref());
+    /// Navigates to a page asynchronously.
+    pub fn navigate_page(&self, page: RachatPages) -> Result<()> {
+        let span = info_span!("navigate", url = page.as_ref());
         let span2 = span.clone();
         let _guard = span2.enter();
         self.with_root_window(move |root_window| {
             let _guard = span.enter();
-            root_window.set_next_url(url.into());
+            root_window.set_next_url(page.into());
+        })?;
+        Ok(())
+    }
+
+    /// Records user activity, restarting the idle logout timer.
+    pub fn mark_active(&self) {
+        if let Some(rachat) = RACHAT.get() {
+            rachat.data_store().mark_active();
+        }
+    }
+
+    /// Logs out whenever the user has been idle for too long, and shows the login page.
+    pub fn spawn_idle_logout(&'static self) {
+        self.spawn(|| async move {
+            let data_store = rachat().data_store();
+            let mut timeouts = pin!(data_store.watch_idle_timeouts());
+            while timeouts.next().await.is_some() {
+                info!("Logging out after being idle");
+                if let Err(e) = Arc::clone(&data_store).logout().await {
+                    warn!("Failed to log out: {e:?}");
+                    if let Err(report_error) = self.set_error_string(format!("{e:#}")) {
+                        warn!("Failed to report error to the UI: {report_error:?}");
+                    }
+                }
+                // Idle logout has to keep working even if the root window is unavailable for a moment
+                if let Err(e) = self.navigate_page(RachatPages::Login) {
+                    warn!("Failed to show the login page: {e:?}");
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// Restores the size and position the root window had when it was last closed.
+    pub fn spawn_restore_window_geometry(&'static self) {
+        self.spawn(|| async move {
+            let geometry = rachat().config().window_geometry().await?;
+            self.with_root_window(move |root_window| {
+                root_window.geometry_restored(
+                    geometry.width,
+                    geometry.height,
+                    geometry.x.unwrap_or_default(),
+                    geometry.y.unwrap_or_default(),
+                    geometry.x.is_some() && geometry.y.is_some(),
+                    geometry.maximized,
+                );
+            })
+        });
+    }
+
+    /// Records a new size and position of the root window, to be restored on the next start.
+    pub fn save_window_geometry(&self, geometry: WindowGeometry) {
+        if let Some(rachat) = RACHAT.get() {
+            rachat.config().set_window_geometry(geometry);
+        }
+    }
+
+    /// Shows an error message in the root window asynchronously.
+    pub fn set_error_string<S>(&self, error: S) -> Result<()>
+    where
+        S: Into<QString> + AsRef<str> + Send + 'static,
+    {
+        self.with_root_window(move |root_window| {
+            root_window.set_error_string(error.into());
         })?;
         Ok(())
     }
@@ -92,6 +160,23 @@ impl AppState {
             }
         });
     }
+
+    /// Spawns a future, showing its error in the root window if it fails.
+    pub fn spawn_reporting<F, Fut>(&'static self, fun: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.spawn(move || async move {
+            let result = fun().await;
+            if let Err(e) = &result {
+                if let Err(report_error) = self.set_error_string(format!("{e:#}")) {
+                    warn!("Failed to report error to the UI: {report_error:?}");
+                }
+            }
+            result
+        });
+    }
 }
 
 impl Default for AppState {
@@ -126,6 +211,13 @@ async fn main() -> Result<()> {
     color_eyre::install()?;
     tracing_subscriber::fmt::init();
 
+    if std::env::args().any(|arg| arg == "--doctor") {
+        for diagnostic in Rachat::doctor().await {
+            println!("{diagnostic}");
+        }
+        return Ok(());
+    }
+
     RACHAT.set(Rachat::new().await?).unwrap();
 
     APP_STATE.spawn(|| async {
@@ -158,5 +250,6 @@ async fn main() -> Result<()> {
         }
     })
     .await?;
+    rachat().shutdown().await;
     Ok(())
 }
@@ -24,3 +24,49 @@ impl From<RachatPages> for QUrl {
         QUrl::from(value.as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RachatPages;
+
+    /// Prefix of the QML files in the qrc resources of the `rs.chir.rachat` module
+    const QRC_PREFIX: &str = "qrc:/qt/qml/rs/chir/rachat/";
+
+    impl RachatPages {
+        /// All pages, for checking that every page is bundled
+        pub const ALL: [Self; 3] = [Self::Root, Self::SelectHomeserver, Self::Login];
+
+        /// Path of the QML file of the page, relative to the crate root
+        ///
+        /// This is the path listed in the `qml_files` of the build script.
+        pub const fn qml_file(self) -> &'static str {
+            match self {
+                Self::Root => "qml/root.qml",
+                Self::SelectHomeserver => "qml/select-homeserver.qml",
+                Self::Login => "qml/login.qml",
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_page_is_bundled() {
+        let build_script = include_str!("../build.rs");
+        for page in RachatPages::ALL {
+            assert_eq!(
+                page.as_ref().strip_prefix(QRC_PREFIX),
+                Some(page.qml_file()),
+                "{page:?} does not point into the qrc resources"
+            );
+            assert!(
+                std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join(page.qml_file())
+                    .is_file(),
+                "{page:?} has no QML file"
+            );
+            assert!(
+                build_script.contains(&format!("\"{}\"", page.qml_file())),
+                "{page:?} is not listed in the build script"
+            );
+        }
+    }
+}
@@ -25,10 +25,9 @@ impl SelectHomeserver {
     pub fn on_homeserver_text_changed(self: Pin<&mut Self>, homeserver: QString) {
         let homeserver = homeserver.to_string();
 
-        if !DataStore::is_valid_homeserver_name(homeserver) {
-            self.set_error_string(QString::from("Invalid homeserver name"));
-        } else {
-            self.set_error_string(QString::from(""));
+        match DataStore::normalize_homeserver_input(homeserver) {
+            Ok(_) => self.set_error_string(QString::from("")),
+            Err(e) => self.set_error_string(QString::from(&e.to_string())),
         }
     }
     pub fn select_homeserver(&self, homeserver: QString) {
@@ -36,14 +35,18 @@ impl SelectHomeserver {
         let thread = self.qt_thread();
         APP_STATE.spawn(|| async move {
             let data_store = crate::rachat().data_store();
-            if let Err(e) = data_store.set_homeserver(&homeserver).await {
+            let result = match DataStore::normalize_homeserver_input(&homeserver) {
+                Ok(server_name) => data_store.set_homeserver(server_name).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
                 warn!("Failed to set homeserver: {e:?}");
                 thread.queue(move |root_window| {
                     let error_msg = format!("Failed to set homeserver: {e}");
                     root_window.set_error_string(QString::from(&error_msg));
                 })?;
             } else {
-                APP_STATE.navigate(RachatPages::Login)?;
+                APP_STATE.navigate_page(RachatPages::Login)?;
             }
             Ok(())
         });